@@ -0,0 +1,7 @@
+//! The `_Unwind_*` entry points of libgcc_s/libunwind, built as a shared and a static
+//! library so custom runtimes can link against this unwinder instead.
+//!
+//! It must be the only unwinder in the process: see `unwind::libunwind_shim` about
+//! the copies Rust's std links on its own.
+
+pub use unwind::libunwind_shim::*;
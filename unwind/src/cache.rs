@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use crate::{StaticReader, UnwindInfo};
+
+/// A least-recently-used cache of evaluated unwind rows, keyed by the address range
+/// each row covers, so repeated traces don't re-run the CFI programs of hot functions.
+///
+/// The cache can be shared between unwinders on different threads. Call `clear()`
+/// after unloading a module, since its rows would otherwise keep being served.
+pub struct UnwindCache {
+    inner: Mutex<Lru>,
+}
+
+struct Lru {
+    capacity: usize,
+    tick: u64,
+    // start address -> (end address, last use, row)
+    entries: BTreeMap<u64, (u64, u64, UnwindInfo<StaticReader>)>,
+    // last use -> start address, oldest first
+    by_use: BTreeMap<u64, u64>,
+}
+
+impl UnwindCache {
+    /// Creates a cache holding at most `capacity` rows.
+    pub fn new(capacity: usize) -> UnwindCache {
+        UnwindCache {
+            inner: Mutex::new(Lru {
+                capacity,
+                tick: 0,
+                entries: BTreeMap::new(),
+                by_use: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut lru) = self.inner.lock() {
+            lru.entries.clear();
+            lru.by_use.clear();
+        }
+    }
+
+    pub(crate) fn get(&self, address: u64) -> Option<UnwindInfo<StaticReader>> {
+        let mut lru = self.inner.lock().ok()?;
+        lru.tick += 1;
+        let tick = lru.tick;
+        let Lru { ref mut entries, ref mut by_use, .. } = *lru;
+        match entries.range_mut(..=address).next_back() {
+            Some((&start, entry)) if address < entry.0 => {
+                by_use.remove(&entry.1);
+                by_use.insert(tick, start);
+                entry.1 = tick;
+                Some(entry.2.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn insert(&self, start: u64, end: u64, info: UnwindInfo<StaticReader>) {
+        let mut lru = match self.inner.lock() {
+            Ok(lru) => lru,
+            Err(_) => return,
+        };
+        if lru.capacity == 0 {
+            return;
+        }
+        if lru.entries.len() >= lru.capacity && !lru.entries.contains_key(&start) {
+            let oldest = lru.by_use.iter().next().map(|(&last_use, &start)| (last_use, start));
+            if let Some((last_use, oldest)) = oldest {
+                lru.by_use.remove(&last_use);
+                lru.entries.remove(&oldest);
+            }
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        if let Some((_, last_use, _)) = lru.entries.insert(start, (end, tick, info)) {
+            lru.by_use.remove(&last_use);
+        }
+        lru.by_use.insert(tick, start);
+    }
+}
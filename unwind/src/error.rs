@@ -0,0 +1,38 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or evaluating the unwind information failed.
+    Gimli(gimli::Error),
+    /// A rule needs the value of a register that was not recovered for this frame.
+    UndefinedRegister(gimli::Register),
+    /// An expression asked for something the unwinder cannot provide.
+    UnsupportedExpression,
+    /// The address is not in the executable segment of any loaded object, e.g. a
+    /// corrupted return address, a data pointer or a file offset.
+    NotCodeAddress(u64),
+    /// The address is not on the stack above the frame being unwound, e.g. an rbp the
+    /// function doesn't use as a frame pointer.
+    NotStackAddress(u64),
+}
+
+impl From<gimli::Error> for Error {
+    fn from(err: gimli::Error) -> Error {
+        Error::Gimli(err)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match *self {
+            Error::Gimli(ref err) => write!(fmt, "{}", err),
+            Error::UndefinedRegister(reg) => write!(fmt, "register {} is undefined in this frame", reg.0),
+            Error::UnsupportedExpression => write!(fmt, "unsupported CFI expression"),
+            Error::NotCodeAddress(addr) => write!(fmt, "0x{:x} is not in any loaded code", addr),
+            Error::NotStackAddress(addr) => write!(fmt, "0x{:x} is not on the stack", addr),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
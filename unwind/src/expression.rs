@@ -0,0 +1,207 @@
+use gimli::{Encoding, Evaluation, EvaluationResult, Expression, Format, Location, Reader};
+use std::ptr;
+
+use crate::registers::Registers;
+use crate::{Error, StaticReader};
+
+// CFI expressions are always 64-bit here, and .eh_frame CIEs are version 1.
+const ENCODING: Encoding = Encoding {
+    address_size: 8,
+    format: Format::Dwarf32,
+    version: 1,
+};
+
+/// Evaluates a CFI expression against the registers of the frame being unwound.
+///
+/// Register rules pass the `cfa`, which is pushed before evaluation starts and also
+/// answers `DW_OP_call_frame_cfa`. CFA rules have none yet.
+/// Returns the value left on top of the stack.
+pub fn evaluate(expression: &Expression<StaticReader>, registers: &Registers, cfa: Option<u64>) -> Result<u64, Error> {
+    let mut eval = Evaluation::new(expression.0, ENCODING);
+    if let Some(cfa) = cfa {
+        eval.set_initial_value(cfa);
+    }
+
+    let mut result = eval.evaluate()?;
+    loop {
+        result = match result {
+            EvaluationResult::Complete => break,
+            EvaluationResult::RequiresMemory { address, size, .. } => {
+                let value = unsafe { read_memory(address, size)? };
+                eval.resume_with_memory(value)?
+            }
+            EvaluationResult::RequiresRegister(register) => {
+                let value = registers.get(register);
+                let value = value.ok_or(Error::UndefinedRegister(register))?;
+                eval.resume_with_register(value)?
+            }
+            EvaluationResult::RequiresCallFrameCfa => match cfa {
+                Some(cfa) => eval.resume_with_call_frame_cfa(cfa)?,
+                // the CFA can't be defined in terms of itself
+                None => return Err(Error::UnsupportedExpression),
+            },
+            // TLS, entry values and friends never show up in CFI.
+            _ => return Err(Error::UnsupportedExpression),
+        };
+    }
+
+    match eval.result().first().map(|piece| &piece.location) {
+        Some(&Location::Address { address }) => Ok(address),
+        Some(&Location::Value { value }) => Ok(value),
+        _ => Err(Error::UnsupportedExpression),
+    }
+}
+
+// Deep enough for the expressions compilers and glibc's assembly put in CFI.
+const STACK_SIZE: usize = 16;
+
+/// Like `evaluate`, but on a fixed stack, for `DwarfUnwinder::trace_into`. Only the
+/// operations that show up in CFI are supported, control flow excluded.
+pub fn evaluate_in_place(expression: &Expression<StaticReader>, registers: &Registers, cfa: Option<u64>) -> Result<u64, Error> {
+    let mut stack = Stack { values: [0; STACK_SIZE], len: 0 };
+    if let Some(cfa) = cfa {
+        stack.push(cfa)?;
+    }
+
+    let mut bytes = expression.0;
+    while !bytes.is_empty() {
+        let op = bytes.read_u8()?;
+        let value = match op {
+            // DW_OP_addr
+            0x03 => bytes.read_u64()?,
+            // DW_OP_deref
+            0x06 => unsafe { read_memory(stack.pop()?, 8)? },
+            // DW_OP_const*
+            0x08 => u64::from(bytes.read_u8()?),
+            0x09 => bytes.read_i8()? as u64,
+            0x0a => u64::from(bytes.read_u16()?),
+            0x0b => bytes.read_i16()? as u64,
+            0x0c => u64::from(bytes.read_u32()?),
+            0x0d => bytes.read_i32()? as u64,
+            0x0e => bytes.read_u64()?,
+            0x0f => bytes.read_i64()? as u64,
+            0x10 => bytes.read_uleb128()?,
+            0x11 => bytes.read_sleb128()? as u64,
+            // DW_OP_dup, DW_OP_drop, DW_OP_over, DW_OP_pick
+            0x12 => stack.peek(0)?,
+            0x13 => {
+                stack.pop()?;
+                continue;
+            }
+            0x14 => stack.peek(1)?,
+            0x15 => stack.peek(bytes.read_u8()? as usize)?,
+            // DW_OP_swap
+            0x16 => {
+                let (a, b) = (stack.pop()?, stack.pop()?);
+                stack.push(a)?;
+                b
+            }
+            // DW_OP_rot
+            0x17 => {
+                let (a, b, c) = (stack.pop()?, stack.pop()?, stack.pop()?);
+                stack.push(a)?;
+                stack.push(c)?;
+                b
+            }
+            // DW_OP_abs, DW_OP_neg, DW_OP_not
+            0x19 => (stack.pop()? as i64).wrapping_abs() as u64,
+            0x1f => (stack.pop()? as i64).wrapping_neg() as u64,
+            0x20 => !stack.pop()?,
+            // DW_OP_plus_uconst
+            0x23 => stack.pop()?.wrapping_add(bytes.read_uleb128()?),
+            // the binary operations, top of the stack on the right
+            0x1a..=0x1e | 0x21 | 0x22 | 0x24..=0x27 | 0x29..=0x2e => {
+                let (b, a) = (stack.pop()?, stack.pop()?);
+                binary_op(op, a, b)?
+            }
+            // DW_OP_lit*
+            0x30..=0x4f => u64::from(op - 0x30),
+            // DW_OP_breg*
+            0x70..=0x8f => register_plus(registers, gimli::Register(u16::from(op - 0x70)), bytes.read_sleb128()?)?,
+            // DW_OP_bregx
+            0x92 => {
+                let register = gimli::Register(bytes.read_uleb128()? as u16);
+                register_plus(registers, register, bytes.read_sleb128()?)?
+            }
+            // DW_OP_deref_size
+            0x94 => {
+                let size = bytes.read_u8()?;
+                unsafe { read_memory(stack.pop()?, size)? }
+            }
+            // DW_OP_nop
+            0x96 => continue,
+            // DW_OP_call_frame_cfa
+            0x9c => cfa.ok_or(Error::UnsupportedExpression)?,
+            _ => return Err(Error::UnsupportedExpression),
+        };
+        stack.push(value)?;
+    }
+    stack.peek(0)
+}
+
+fn register_plus(registers: &Registers, register: gimli::Register, offset: i64) -> Result<u64, Error> {
+    let value = registers.get(register).ok_or(Error::UndefinedRegister(register))?;
+    Ok(value.wrapping_add(offset as u64))
+}
+
+fn binary_op(op: u8, a: u64, b: u64) -> Result<u64, Error> {
+    let (sa, sb) = (a as i64, b as i64);
+    Ok(match op {
+        0x1a => a & b,
+        0x1b => sa.checked_div(sb).ok_or(Error::UnsupportedExpression)? as u64,
+        0x1c => a.wrapping_sub(b),
+        0x1d => a.checked_rem(b).ok_or(Error::UnsupportedExpression)?,
+        0x1e => a.wrapping_mul(b),
+        0x21 => a | b,
+        0x22 => a.wrapping_add(b),
+        0x24 => a.checked_shl(b as u32).filter(|_| b < 64).unwrap_or(0),
+        0x25 => a.checked_shr(b as u32).filter(|_| b < 64).unwrap_or(0),
+        0x26 => sa.checked_shr(b as u32).filter(|_| b < 64).unwrap_or(if sa < 0 { -1 } else { 0 }) as u64,
+        0x27 => a ^ b,
+        0x29 => (a == b) as u64,
+        0x2a => (sa >= sb) as u64,
+        0x2b => (sa > sb) as u64,
+        0x2c => (sa <= sb) as u64,
+        0x2d => (sa < sb) as u64,
+        0x2e => (a != b) as u64,
+        _ => return Err(Error::UnsupportedExpression),
+    })
+}
+
+struct Stack {
+    values: [u64; STACK_SIZE],
+    len: usize,
+}
+
+impl Stack {
+    fn push(&mut self, value: u64) -> Result<(), Error> {
+        *self.values.get_mut(self.len).ok_or(Error::UnsupportedExpression)? = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u64, Error> {
+        self.len = self.len.checked_sub(1).ok_or(Error::Gimli(gimli::Error::PopWithEmptyStack))?;
+        self.peek_at(self.len)
+    }
+
+    // `depth` values below the top
+    fn peek(&self, depth: usize) -> Result<u64, Error> {
+        let index = self.len.checked_sub(depth + 1).ok_or(Error::Gimli(gimli::Error::PopWithEmptyStack))?;
+        self.peek_at(index)
+    }
+
+    fn peek_at(&self, index: usize) -> Result<u64, Error> {
+        self.values.get(index).copied().ok_or(Error::Gimli(gimli::Error::PopWithEmptyStack))
+    }
+}
+
+unsafe fn read_memory(address: u64, size: u8) -> Result<u64, Error> {
+    Ok(match size {
+        1 => ptr::read_unaligned(address as *const u8) as u64,
+        2 => ptr::read_unaligned(address as *const u16) as u64,
+        4 => ptr::read_unaligned(address as *const u32) as u64,
+        8 => ptr::read_unaligned(address as *const u64),
+        _ => return Err(Error::UnsupportedExpression),
+    })
+}
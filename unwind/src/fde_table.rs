@@ -0,0 +1,55 @@
+use gimli::{BaseAddresses, CieOrFde, FrameDescriptionEntry, UnwindSection};
+
+use crate::{AddrRange, StaticReader};
+
+/// FDEs sorted by address, for sections that come without a search table
+/// (.debug_frame, or .eh_frame without .eh_frame_hdr).
+pub struct FdeTable {
+    fdes: Vec<FrameDescriptionEntry<StaticReader>>,
+}
+
+impl FdeTable {
+    pub fn new<S: UnwindSection<StaticReader>>(section: &S, bases: &BaseAddresses) -> FdeTable {
+        let mut fdes = Vec::new();
+        let mut entries = section.entries(bases);
+        loop {
+            match entries.next() {
+                Ok(Some(CieOrFde::Fde(partial))) => match partial.parse(S::cie_from_offset) {
+                    Ok(fde) => fdes.push(fde),
+                    Err(e) => debug!("skipping bad FDE: {}", e),
+                },
+                Ok(Some(CieOrFde::Cie(_))) => (),
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("stopped reading CFI entries: {}", e);
+                    break;
+                }
+            }
+        }
+        fdes.sort_by_key(|fde| fde.initial_address());
+        trace!("built FDE table with {} entries", fdes.len());
+        FdeTable { fdes }
+    }
+
+    /// The addresses covered by the entries, holes included. Entries running past the end
+    /// of the address space are left out.
+    pub fn range(&self) -> Option<AddrRange> {
+        let start = self.fdes.first()?.initial_address();
+        let end = self.fdes.iter().filter_map(|fde| fde.initial_address().checked_add(fde.len())).max()?;
+        Some(AddrRange { start, end })
+    }
+
+    pub fn fde_for_address(&self, address: u64) -> gimli::Result<FrameDescriptionEntry<StaticReader>> {
+        let i = match self.fdes.binary_search_by_key(&address, |fde| fde.initial_address()) {
+            Ok(i) => i,
+            Err(0) => return Err(gimli::Error::NoUnwindInfoForAddress),
+            Err(i) => i - 1,
+        };
+        let fde = self.fdes.get(i).ok_or(gimli::Error::NoUnwindInfoForAddress)?;
+        if address - fde.initial_address() < fde.len() {
+            Ok(fde.clone())
+        } else {
+            Err(gimli::Error::NoUnwindInfoForAddress)
+        }
+    }
+}
@@ -26,10 +26,31 @@ pub fn find_cfi_sections() -> Vec<EhRef> {
 
         cfi.push(EhRef {
             text,
-            eh_frame_hdr,
+            eh_frame_hdr: Some(eh_frame_hdr),
             eh_frame_end,
+            bias: 0,
+            path: None,
+            build_id: None,
         });
     }
     trace!("CFI sections: {:?}", cfi);
     cfi
 }
+
+pub fn loaded_generation() -> Option<u64> {
+    // the linker script's sections are all there is
+    Some(0)
+}
+
+pub fn load_debug_frame(_er: &EhRef) -> Option<Box<[u8]>> {
+    // Nothing to read it from; only what the linker script maps is available.
+    None
+}
+
+pub fn eh_frame_section(_er: &EhRef) -> Option<AddrRange> {
+    None
+}
+
+pub fn jit_eh_frames() -> Vec<AddrRange> {
+    Vec::new()
+}
@@ -1,5 +1,9 @@
 use libc::{c_void, c_int, c_char};
-use std::ffi::CStr;
+use std::ffi::{CStr, OsStr};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
 use std::{slice, mem, cmp};
 use range::AddrRange;
 use super::EhRef;
@@ -12,6 +16,14 @@ struct DlPhdrInfo {
     phnum: u16,
 }
 
+// The counters glibc and musl append, which older C libraries may not pass.
+#[repr(C)]
+struct DlPhdrInfoCounts {
+    info: DlPhdrInfo,
+    adds: u64,
+    subs: u64,
+}
+
 /*
 #[repr(C)]
 struct Phdr32 {
@@ -40,6 +52,8 @@ struct Phdr64 {
 
 const PT_GNU_EH_FRAME: u32 = 0x6474e550;
 const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_GNU_BUILD_ID: u32 = 3;
 const PF_X: u32 = 1;
 
 type PhdrCb = extern "C" fn(info: *const DlPhdrInfo, size: usize, data: *mut c_void) -> c_int;
@@ -49,7 +63,10 @@ extern "C" {
 
 extern "C" fn callback(info: *const DlPhdrInfo, size: usize, data: *mut c_void) -> c_int {
     let data = data as *mut Vec<EhRef>;
-    assert!(size >= mem::size_of::<DlPhdrInfo>());
+    if size < mem::size_of::<DlPhdrInfo>() {
+        error!("dl_phdr_info is smaller than expected ({} bytes)", size);
+        return 1; // stop iterating, every object would be the same
+    }
 
     unsafe {
         let name = CStr::from_ptr((*info).name);
@@ -58,28 +75,246 @@ extern "C" fn callback(info: *const DlPhdrInfo, size: usize, data: *mut c_void)
         let phdr = slice::from_raw_parts((*info).phdr, (*info).phnum as usize);
 
         if let Some(text) = phdr.iter().filter(|x| x.type_ == PT_LOAD && x.flags & PF_X != 0).next() {
-            if let Some(eh_frame_hdr) = phdr.iter().filter(|x| x.type_ == PT_GNU_EH_FRAME).next() {
-                let start_addr = (*info).addr + text.vaddr;
+            let start_addr = (*info).addr + text.vaddr;
+            // Objects without .eh_frame_hdr may still carry .debug_frame on disk.
+            let eh_frame_hdr = phdr.iter().filter(|x| x.type_ == PT_GNU_EH_FRAME).next().map(|eh_frame_hdr| {
                 let eh_frame_hdr_start = (*info).addr + eh_frame_hdr.vaddr;
-                let max_vaddr = phdr.iter().filter(|x| x.type_ == PT_LOAD)
-                    .fold(0, |vaddr, x| cmp::max(vaddr, x.vaddr + x.memsz));
-                // This is an upper bound, not the exact address.
-                let eh_frame_end = (*info).addr + max_vaddr;
-                (*data).push(EhRef {
-                    text: AddrRange { start: start_addr, end: start_addr + text.memsz },
-                    eh_frame_hdr: AddrRange { start: eh_frame_hdr_start, end: eh_frame_hdr_start + eh_frame_hdr.memsz },
-                    eh_frame_end,
-                });
-            }
+                AddrRange { start: eh_frame_hdr_start, end: eh_frame_hdr_start + eh_frame_hdr.memsz }
+            });
+            let max_vaddr = phdr.iter().filter(|x| x.type_ == PT_LOAD)
+                .fold(0, |vaddr, x| cmp::max(vaddr, x.vaddr + x.memsz));
+            // This is an upper bound, not the exact address.
+            let eh_frame_end = (*info).addr + max_vaddr;
+            // The main program reports an empty name.
+            let path = match name.to_bytes() {
+                b"" => PathBuf::from("/proc/self/exe"),
+                name => PathBuf::from(OsStr::from_bytes(name)),
+            };
+            (*data).push(EhRef {
+                text: AddrRange { start: start_addr, end: start_addr + text.memsz },
+                eh_frame_hdr,
+                eh_frame_end,
+                bias: (*info).addr,
+                path: Some(path),
+                build_id: phdr.iter().filter(|x| x.type_ == PT_NOTE)
+                    .filter_map(|x| build_id((*info).addr + x.vaddr, x.memsz))
+                    .next(),
+            });
         }
 
         0
     }
 }
 
+// Looks for the GNU build ID in a PT_NOTE segment mapped at `start`.
+unsafe fn build_id(start: u64, len: u64) -> Option<Vec<u8>> {
+    let align = |x: u64| (x + 3) & !3;
+    let mut note = start;
+    while note + 12 <= start + len {
+        let namesz = *(note as *const u32) as u64;
+        let descsz = *((note + 4) as *const u32) as u64;
+        let type_ = *((note + 8) as *const u32);
+        let name = note + 12;
+        let desc = name + align(namesz);
+        if desc + descsz > start + len {
+            break;
+        }
+        if type_ == NT_GNU_BUILD_ID && slice::from_raw_parts(name as *const u8, namesz as usize) == b"GNU\0" {
+            return Some(slice::from_raw_parts(desc as *const u8, descsz as usize).to_vec());
+        }
+        note = desc + align(descsz);
+    }
+    None
+}
+
+extern "C" fn counts_callback(info: *const DlPhdrInfo, size: usize, data: *mut c_void) -> c_int {
+    if size >= mem::size_of::<DlPhdrInfoCounts>() {
+        unsafe {
+            let info = info as *const DlPhdrInfoCounts;
+            *(data as *mut Option<u64>) = Some((*info).adds.wrapping_add((*info).subs));
+        }
+    }
+    1 // every object reports the same counters
+}
+
+/// Changes whenever an object is loaded or unloaded, to tell whether what
+/// `find_cfi_sections` returned is out of date. `None` if the C library doesn't say.
+pub fn loaded_generation() -> Option<u64> {
+    let mut generation = None;
+    unsafe { dl_iterate_phdr(counts_callback, &mut generation as *mut _ as *mut c_void) };
+    generation
+}
+
 pub fn find_cfi_sections() -> Vec<EhRef> {
     let mut cfi: Vec<EhRef> = Vec::new();
     unsafe { dl_iterate_phdr(callback, &mut cfi as *mut _ as *mut c_void) };
     trace!("CFI sections: {:?}", cfi);
     cfi
 }
+
+const SHT_NOBITS: u32 = 8;
+const SHDR64_SIZE: usize = 64;
+
+// The offsets and sizes come from the file itself, so they are checked against its
+// length before anything is allocated.
+fn read_at(file: &mut File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    if offset.checked_add(len as u64)? > file.metadata().ok()?.len() {
+        return None;
+    }
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut buf).ok()?;
+    Some(buf)
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> Option<u16> {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(buf.get(offset..offset + 2)?);
+    Some(u16::from_ne_bytes(bytes))
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(buf.get(offset..offset + 4)?);
+    Some(u32::from_ne_bytes(bytes))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(buf.get(offset..offset + 8)?);
+    Some(u64::from_ne_bytes(bytes))
+}
+
+struct Section {
+    name: u32,
+    type_: u32,
+    addr: u64,
+    offset: u64,
+    size: u64,
+}
+
+// Looks up a section by name in the object's section headers on disk.
+fn find_section(er: &EhRef, wanted: &[u8]) -> Option<(File, Section)> {
+    let mut file = File::open(er.path.as_ref()?).ok()?;
+    let section = lookup_section(|offset, len| read_at(&mut file, offset, len), wanted)?;
+    Some((file, section))
+}
+
+// Looks up a section by name in an object image in memory.
+fn image_section(image: &[u8], wanted: &[u8]) -> Option<Section> {
+    lookup_section(|offset, len| {
+        let offset = offset as usize;
+        image.get(offset..offset.checked_add(len)?).map(<[u8]>::to_vec)
+    }, wanted)
+}
+
+// Parses the section headers, with `read(offset, len)` reading the object's bytes.
+fn lookup_section<F>(mut read: F, wanted: &[u8]) -> Option<Section>
+    where F: FnMut(u64, usize) -> Option<Vec<u8>>
+{
+    let ehdr = read(0, 64)?;
+    if !ehdr.starts_with(b"\x7fELF\x02") {
+        return None;
+    }
+    let shoff = read_u64(&ehdr, 0x28)?;
+    let shentsize = read_u16(&ehdr, 0x3a)? as usize;
+    let shnum = read_u16(&ehdr, 0x3c)? as usize;
+    let shstrndx = read_u16(&ehdr, 0x3e)? as usize;
+    if shentsize != SHDR64_SIZE || shstrndx >= shnum {
+        return None;
+    }
+
+    let shdrs = read(shoff, shnum * SHDR64_SIZE)?;
+    let mut shdrs: Vec<Section> = shdrs.chunks(SHDR64_SIZE).map(|shdr| Some(Section {
+        name: read_u32(shdr, 0)?,
+        type_: read_u32(shdr, 4)?,
+        addr: read_u64(shdr, 0x10)?,
+        offset: read_u64(shdr, 0x18)?,
+        size: read_u64(shdr, 0x20)?,
+    })).collect::<Option<_>>()?;
+
+    let strtab = shdrs.get(shstrndx)?;
+    let names = read(strtab.offset, strtab.size as usize)?;
+    let index = shdrs.iter().position(|shdr| {
+        names.get(shdr.name as usize..).map_or(false, |name| {
+            name.starts_with(wanted) && name.get(wanted.len()) == Some(&0)
+        })
+    })?;
+    // Stripped packages may keep the header but not the contents.
+    if shdrs.get(index)?.type_ == SHT_NOBITS {
+        return None;
+    }
+    Some(shdrs.swap_remove(index))
+}
+
+/// Reads the .debug_frame section of the object on disk, since it is never mapped.
+pub fn load_debug_frame(er: &EhRef) -> Option<Box<[u8]>> {
+    let (mut file, section) = find_section(er, b".debug_frame")?;
+    trace!("{:?}: .debug_frame at 0x{:x} sz {:x}", er.path, section.offset, section.size);
+    read_at(&mut file, section.offset, section.size as usize).map(Vec::into_boxed_slice)
+}
+
+/// Finds where .eh_frame is mapped, for objects without a PT_GNU_EH_FRAME segment.
+pub fn eh_frame_section(er: &EhRef) -> Option<AddrRange> {
+    let (_, section) = find_section(er, b".eh_frame")?;
+    let start = er.bias.wrapping_add(section.addr);
+    trace!("{:?}: .eh_frame at 0x{:x} sz {:x}", er.path, start, section.size);
+    Some(AddrRange { start, end: start.checked_add(section.size)? })
+}
+
+// The GDB JIT interface ("JIT Compilation Interface" in the GDB manual).
+#[repr(C)]
+struct JitCodeEntry {
+    next: *const JitCodeEntry,
+    prev: *const JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64,
+}
+
+#[repr(C)]
+struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *const JitCodeEntry,
+    first_entry: *const JitCodeEntry,
+}
+
+/// Finds the .eh_frame sections of the objects JITs (LLVM, V8, ...) announced to
+/// debuggers through `__jit_debug_descriptor`, where they are loaded.
+///
+/// The descriptor must be exported dynamically to be found. The list isn't locked,
+/// so JITs must not change it while this runs.
+pub fn jit_eh_frames() -> Vec<AddrRange> {
+    let mut frames = Vec::new();
+    unsafe {
+        let name = b"__jit_debug_descriptor\0";
+        let descriptor = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr() as *const c_char) as *const JitDescriptor;
+        if descriptor.is_null() {
+            return frames;
+        }
+        let mut entry = (*descriptor).first_entry;
+        while !entry.is_null() {
+            let (addr, size) = ((*entry).symfile_addr, (*entry).symfile_size as usize);
+            // a registration in progress, or a JIT that doesn't emit objects
+            if addr.is_null() || size == 0 {
+                trace!("JIT entry at {:p} has no object", entry);
+                entry = (*entry).next;
+                continue;
+            }
+            let image = slice::from_raw_parts(addr, size);
+            // The JIT patched in the addresses the sections were loaded at.
+            match image_section(image, b".eh_frame") {
+                Some(section) if section.addr != 0 => match section.addr.checked_add(section.size) {
+                    Some(end) => {
+                        trace!("JIT object at {:p}: .eh_frame at 0x{:x} sz {:x}", image.as_ptr(), section.addr, section.size);
+                        frames.push(AddrRange { start: section.addr, end });
+                    }
+                    None => trace!("JIT object at {:p}: .eh_frame runs past the address space", image.as_ptr()),
+                },
+                _ => trace!("JIT object at {:p} has no loaded .eh_frame", image.as_ptr()),
+            }
+            entry = (*entry).next;
+        }
+    }
+    frames
+}
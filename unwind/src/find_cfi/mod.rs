@@ -1,10 +1,17 @@
 use range::AddrRange;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub struct EhRef {
     pub text: AddrRange,
-    pub eh_frame_hdr: AddrRange,
+    pub eh_frame_hdr: Option<AddrRange>,
     pub eh_frame_end: u64,
+    /// Difference between runtime and link-time addresses.
+    pub bias: u64,
+    /// Where to look for sections that aren't loaded at runtime (e.g. .debug_frame).
+    pub path: Option<PathBuf>,
+    /// Contents of the NT_GNU_BUILD_ID note.
+    pub build_id: Option<Vec<u8>>,
 }
 
 #[cfg(unix)]
@@ -16,4 +23,4 @@ mod imp;
 mod imp;
 
 
-pub use self::imp::find_cfi_sections;
+pub use self::imp::{find_cfi_sections, loaded_generation, load_debug_frame, eh_frame_section, jit_eh_frames};
@@ -1,5 +1,6 @@
 use gimli::X86_64;
 use crate::registers::Registers;
+use crate::Error;
 
 type UnwindPayload<'a> = &'a mut dyn FnMut(Registers);
 
@@ -115,8 +116,12 @@ pub unsafe extern "C" fn unwind_recorder(payload: *mut UnwindPayload, stack: u64
     payload(registers);
 }
 
-pub unsafe fn land(regs: &Registers) {
-    let mut lr = LandingRegisters {
+/// Resumes execution with `regs`. Only returns if the stack pointer or the return
+/// address is unknown, or the stack pointer has no room below it for the return address.
+pub unsafe fn land(regs: &Registers) -> Result<(), Error> {
+    let rsp = regs[X86_64::RSP].ok_or(Error::UndefinedRegister(X86_64::RSP))?;
+    let ra = regs[X86_64::RA].ok_or(Error::UndefinedRegister(X86_64::RA))?;
+    let lr = LandingRegisters {
         rax: regs[X86_64::RAX].unwrap_or(0),
         rbx: regs[X86_64::RBX].unwrap_or(0),
         rcx: regs[X86_64::RCX].unwrap_or(0),
@@ -132,9 +137,9 @@ pub unsafe fn land(regs: &Registers) {
         r13: regs[X86_64::R13].unwrap_or(0),
         r14: regs[X86_64::R14].unwrap_or(0),
         r15: regs[X86_64::R15].unwrap_or(0),
-        rsp: regs[X86_64::RSP].unwrap(),
+        rsp: rsp.checked_sub(8).ok_or(Error::NotStackAddress(rsp))?,
     };
-    lr.rsp -= 8;
-    *(lr.rsp as *mut u64) = regs[X86_64::RA].unwrap();
+    *(lr.rsp as *mut u64) = ra;
     unwind_lander(&lr);
+    Ok(())
 }
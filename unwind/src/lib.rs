@@ -1,20 +1,46 @@
 #![cfg_attr(feature = "nightly", feature(unwind_attributes))]
 #![cfg_attr(feature = "asm", feature(asm, naked_functions))]
+// Unwinding runs on corrupt stacks, in signal handlers and under foreign frames, where a
+// panic only makes things worse: bad input must come back as an Error.
+#![deny(clippy::panic, clippy::unwrap_used, clippy::expect_used, clippy::indexing_slicing)]
 
 pub use fallible_iterator::FallibleIterator;
 
 #[macro_use] extern crate log;
 
-use gimli::{UnwindSection, UnwindTable, UnwindTableRow, EhFrame, BaseAddresses, UninitializedUnwindContext, Pointer, Reader, EndianSlice, NativeEndian, CfaRule, RegisterRule, EhFrameHdr, ParsedEhFrameHdr};
+use std::sync::Arc;
 
-pub use gimli::X86_64;
+use gimli::{UnwindSection, UnwindTable, UnwindTableRow, EhFrame, DebugFrame, BaseAddresses, UninitializedUnwindContext, Pointer, Reader, EndianSlice, NativeEndian, CfaRule, RegisterRule, EhFrameHdr, ParsedEhFrameHdr, FrameDescriptionEntry};
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+/// DWARF register numbers, of which `X86_64` names the ones of this architecture.
+pub use gimli::{Register, X86_64};
 
 mod registers;
 mod find_cfi;
 mod range;
+mod error;
+mod expression;
+mod fde_table;
+mod cache;
+mod trace;
+mod signal;
+mod registry;
+mod row;
+mod stack;
 pub mod glue;
+pub mod lsda;
 pub use registers::Registers;
+pub use trace::{Backtrace, ProcessTrace, ProcessMetadata, ThreadTrace, TraceFrame, TraceModule};
+pub use range::AddrRange;
+pub use error::Error;
+pub use cache::UnwindCache;
+pub use registry::{register_frame, deregister_frame};
 use find_cfi::EhRef;
+use fde_table::FdeTable;
+use row::FixedRow;
 
 #[cfg(feature = "libunwind_shim")]
 pub mod libunwind_shim;
@@ -23,81 +49,573 @@ pub mod libunwind_shim;
 pub struct StackFrames<'a> {
     unwinder: &'a mut DwarfUnwinder,
     registers: Registers,
-    state: Option<(UnwindTableRow<StaticReader>, u64)>,
+    state: Option<(Step, u64, bool)>,
+    depth: usize,
+    // CFA and caller address of the previous frame
+    last_frame: Option<(u64, u64)>,
+    stop_reason: Option<StopReason>,
+    // the first return address is really the address of an interrupted instruction
+    interrupted: bool,
+    // the same, for the frame last returned
+    returned_interrupted: bool,
+}
+
+// How to get from a frame to its caller, given the frame's CFA.
+enum Step {
+    Cfi(UnwindTableRow<StaticReader>),
+    FramePointer,
+    // return address just below the CFA, everything else unknown or unchanged
+    Scan,
+    // everything restored from the ucontext at this address
+    Sigreturn(u64),
+    Hint(Arc<UnwindHint>),
+}
+
+/// Why a `StackFrames` iteration ended without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub enum StopReason {
+    /// The outermost frame has no return address (undefined or 0).
+    EndOfStack,
+    /// The CFA did not move up the stack, or a frame repeated, so unwinding would loop forever.
+    Cycle,
+    /// The unwinder's frame limit was reached.
+    MaxDepth,
+    /// The CFA is not a plausible stack address.
+    BadCfa,
+    /// The unwinder's stop condition said so.
+    Requested,
+}
+
+/// How a frame was recovered, i.e. how far its registers can be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub enum FrameTrust {
+    /// Stepped using the caller's CFI.
+    Cfi,
+    /// Found by following the rbp chain, which only works if the caller keeps a frame pointer.
+    FramePointer,
+    /// A guess: the first word on the stack that looked like a code address.
+    Scanned,
+    /// A signal frame, restored from the context the kernel saved.
+    Signal,
+}
+
+/// How the frame last returned by `StackFrames::next()` saved one of its caller's registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub enum RegisterRecovery {
+    /// The caller's value is lost.
+    Undefined,
+    /// The frame didn't touch the register.
+    SameValue,
+    /// Saved in memory at CFA + offset.
+    Offset(i64),
+    /// The value is CFA + offset itself.
+    ValOffset(i64),
+    /// Held in another register of this frame.
+    Register(u16),
+    /// Saved in memory at an address computed by a DWARF expression.
+    Expression,
+    /// The value is computed by a DWARF expression.
+    ValExpression,
+    /// Recovered by a rule the architecture's ABI defines. None exist on x86_64, so the
+    /// value is treated as lost.
+    Architectural,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StackFrame {
     personality: Option<u64>,
     lsda: Option<u64>,
     initial_address: u64,
+    cfa: u64,
+    args_size: u64,
+    signal_trampoline: bool,
+    trust: FrameTrust,
 }
 
 impl StackFrame {
+    /// Address of the personality routine named in the CIE augmentation (`P`), if any.
     pub fn personality(&self) -> Option<u64> {
         self.personality
     }
 
+    /// Address of the language-specific data area from the FDE augmentation (`L`), if any.
+    /// A frame may have a personality routine but no LSDA.
     pub fn lsda(&self) -> Option<u64> {
         self.lsda
     }
 
+    /// Start of the function, or 0 if the frame was not recovered from CFI.
     pub fn initial_address(&self) -> u64 {
         self.initial_address
     }
+
+    /// The canonical frame address, i.e. the stack pointer in the caller before the call.
+    pub fn cfa(&self) -> u64 {
+        self.cfa
+    }
+
+    /// Bytes of outgoing arguments pushed at the call (`DW_CFA_GNU_args_size`). Landing
+    /// pads expect them popped, so resuming in this frame adds this to the stack pointer.
+    pub fn args_size(&self) -> u64 {
+        self.args_size
+    }
+
+    /// Whether this frame is a signal trampoline (CIE augmentation `S`), in which case
+    /// the caller's return address points at the interrupted instruction itself.
+    pub fn is_signal_trampoline(&self) -> bool {
+        self.signal_trampoline
+    }
+
+    pub fn trust(&self) -> FrameTrust {
+        self.trust
+    }
 }
 
-pub trait Unwinder: Default {
+/// Implemented by the unwinders of this crate only, so that methods can be added.
+pub trait Unwinder: Default + private::Sealed {
     fn trace<F>(&mut self, f: F) where F: FnMut(&mut StackFrames);
 }
 
+mod private {
+    pub trait Sealed {}
+}
+
 type StaticReader = EndianSlice<'static, NativeEndian>;
 
 struct ObjectRecord {
     er: EhRef,
-    eh_frame_hdr: ParsedEhFrameHdr<StaticReader>,
-    eh_frame: EhFrame<StaticReader>,
+    eh_frame: Option<(EhFrame<StaticReader>, FdeIndex)>,
+    // Loaded from disk on the first address .eh_frame can't answer for; the outer
+    // option records whether we tried yet.
+    debug_frame: Option<Option<(Box<[u8]>, DebugFrame<StaticReader>, FdeTable)>>,
     bases: BaseAddresses,
+    // from `register_frame`
+    registered: bool,
+}
+
+enum FdeIndex {
+    // The binary search table from .eh_frame_hdr.
+    Hdr(ParsedEhFrameHdr<StaticReader>),
+    // Built at load time when there is none.
+    Sorted(FdeTable),
+}
+
+/// Given the registers on reaching a context switch routine, returns the registers of
+/// the context to continue unwinding in.
+pub type ContextSwitchHook = dyn Fn(&Registers) -> Option<Registers>;
+
+/// Decides, given a frame and its registers, whether the trace goes on.
+pub type StopCondition = dyn Fn(&StackFrame, &Registers) -> Control;
+
+/// The answer of a `StopCondition`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Continue,
+    /// End the trace before this frame (`StopReason::Requested`).
+    Stop,
 }
 
 pub struct DwarfUnwinder {
     cfi: Vec<ObjectRecord>,
     ctx: UninitializedUnwindContext<StaticReader>,
+    context_switches: Vec<(AddrRange, Box<ContextSwitchHook>)>,
+    stop_condition: Option<Box<StopCondition>>,
+    max_frames: Option<usize>,
+    skip_frames: usize,
+    strategies: Arc<[Strategy]>,
+    strategy_overrides: Vec<(AddrRange, Arc<[Strategy]>)>,
+    unwind_hints: Vec<(AddrRange, Arc<UnwindHint>)>,
+    cache: Option<Arc<UnwindCache>>,
+    // the stack of the thread that created the unwinder
+    stack: Option<AddrRange>,
+    // of the registered blobs in `cfi`
+    registry_generation: usize,
+}
+
+/// A way of recovering a frame's caller. The unwinder tries its strategies in order
+/// for each frame until one succeeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Strategy {
+    /// Evaluate the CFI (.eh_frame, .debug_frame) covering the address.
+    Cfi,
+    /// Follow the rbp chain: saved rbp at [rbp], return address at [rbp + 8].
+    /// Only correct if the function keeps a frame pointer. An rbp that doesn't point into
+    /// the stack above the frame is rejected.
+    FramePointer,
+    /// Take the first of the next `SCAN_WORDS` stack words that points into the code of
+    /// a loaded object as the return address. A last resort, which can produce bogus
+    /// frames and reads stack memory the frame may not own. The scan stops at the end of
+    /// the stack, so it needs to know where that is: it only works on the stack of the
+    /// thread that created the unwinder and on its signal stack.
+    StackScan,
+}
+
+/// How many stack words `Strategy::StackScan` looks at, at most.
+pub const SCAN_WORDS: u64 = 64;
+
+/// A recipe for unwinding through code that ships without CFI, like syscall shims and
+/// context switch stubs: "CFA = rsp + 32, return address at CFA - 8".
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct UnwindHint {
+    pub cfa_register: gimli::Register,
+    pub cfa_offset: i64,
+    /// The registers saved on the stack, with their offsets from the CFA. The return
+    /// address is at -8 unless `X86_64::RA` is listed.
+    pub saved: Vec<(gimli::Register, i64)>,
+}
+
+impl UnwindHint {
+    pub fn new(cfa_register: Register, cfa_offset: i64, saved: Vec<(Register, i64)>) -> UnwindHint {
+        UnwindHint { cfa_register, cfa_offset, saved }
+    }
 }
 
 impl Default for DwarfUnwinder {
     fn default() -> DwarfUnwinder {
-        let cfi = find_cfi::find_cfi_sections().into_iter().map(|er| {
-            unsafe {
-                // TODO: set_got()
-                let bases = BaseAddresses::default()
-                    .set_eh_frame_hdr(er.eh_frame_hdr.start)
-                    .set_text(er.text.start);
+        let mut cfi: Vec<_> = find_cfi::find_cfi_sections().into_iter().map(|er| unsafe { ObjectRecord::new(er) }).collect();
+        cfi.extend(find_cfi::jit_eh_frames().into_iter().filter_map(|range| unsafe { ObjectRecord::from_eh_frame(range) }));
+        let (registry_generation, registered) = registry::registered();
+        cfi.extend(registered.into_iter()
+            .filter_map(|range| unsafe { ObjectRecord::from_eh_frame(range) })
+            .map(|rec| ObjectRecord { registered: true, ..rec }));
+
+        DwarfUnwinder {
+            cfi,
+            ctx: UninitializedUnwindContext::new(),
+            context_switches: Vec::new(),
+            stop_condition: None,
+            max_frames: None,
+            skip_frames: 0,
+            strategies: Arc::new([Strategy::Cfi]),
+            strategy_overrides: Vec::new(),
+            unwind_hints: Vec::new(),
+            cache: None,
+            stack: stack::thread_stack(),
+            registry_generation,
+        }
+    }
+}
+
+impl DwarfUnwinder {
+    /// Registers a user-space context switch routine (the entry trampoline of
+    /// `makecontext`, a fiber switcher, ...) occupying `range`.
+    ///
+    /// When a return address lands in `range`, `hook` may hand back the registers of the
+    /// context that was switched away from (e.g. the scheduler that started a fiber), and
+    /// unwinding continues on that stack. Returning `None` unwinds through the routine
+    /// using its CFI as usual.
+    pub fn add_context_switch<F>(&mut self, range: AddrRange, hook: F)
+        where F: Fn(&Registers) -> Option<Registers> + 'static
+    {
+        self.context_switches.push((range, Box::new(hook)));
+    }
 
-                let eh_frame_hdr: &'static [u8] = std::slice::from_raw_parts(er.eh_frame_hdr.start as *const u8, er.eh_frame_hdr.len() as usize);
+    /// Asks `condition` about every frame before returning it, e.g. to end traces at a
+    /// green thread's entry point or at the boundary of its stack.
+    pub fn set_stop_condition<F>(&mut self, condition: F)
+        where F: Fn(&StackFrame, &Registers) -> Control + 'static
+    {
+        self.stop_condition = Some(Box::new(condition));
+    }
 
-                let eh_frame_hdr = EhFrameHdr::new(eh_frame_hdr, NativeEndian).parse(&bases, 8).unwrap();
+    /// Stops traces after `max_frames` frames (`StopReason::MaxDepth`). Unlimited by default,
+    /// since exception dispatch must be able to reach any frame.
+    pub fn set_max_frames(&mut self, max_frames: Option<usize>) {
+        self.max_frames = max_frames;
+    }
 
-                let eh_frame_addr = deref_ptr(eh_frame_hdr.eh_frame_ptr());
-                let eh_frame_sz = er.eh_frame_end.saturating_sub(eh_frame_addr);
+    /// Leaves out the first `skip_frames` frames of each trace, e.g. the unwinder's own
+    /// and those of a capture wrapper, so traces start in the caller's code.
+    pub fn set_skip_frames(&mut self, skip_frames: usize) {
+        self.skip_frames = skip_frames;
+    }
 
-                let eh_frame: &'static [u8] = std::slice::from_raw_parts(eh_frame_addr as *const u8, eh_frame_sz as usize);
-                trace!("eh_frame at {:p} sz {:x}", eh_frame_addr as *const u8, eh_frame_sz);
-                let eh_frame = EhFrame::new(eh_frame, NativeEndian);
+    /// Sets the strategies tried, in order, for each frame. Defaults to `[Strategy::Cfi]`;
+    /// adding `Strategy::FramePointer` keeps traces going through JITted code, hand-written
+    /// assembly and stripped libraries.
+    pub fn set_strategies(&mut self, strategies: &[Strategy]) {
+        self.strategies = strategies.into();
+    }
+
+    /// Uses `strategies` instead of the default chain for frames in `range`, e.g. a module
+    /// known to have broken CFI or a JIT region. Later overrides take precedence.
+    pub fn override_strategies(&mut self, range: AddrRange, strategies: &[Strategy]) {
+        self.strategy_overrides.push((range, strategies.into()));
+    }
+
+    /// Unwinds frames in `range` with `hint` in place of CFI (under `Strategy::Cfi`).
+    /// Later hints take precedence.
+    pub fn add_unwind_hint(&mut self, range: AddrRange, hint: UnwindHint) {
+        self.unwind_hints.push((range, Arc::new(hint)));
+    }
+
+    fn strategies_for(&self, address: u64) -> Arc<[Strategy]> {
+        self.strategy_overrides.iter().rev()
+            .find(|x| x.0.contains(address))
+            .map_or(&self.strategies, |x| &x.1)
+            .clone()
+    }
+
+    // Finds the frame at `caller`, returning how to step past it and its CFA.
+    fn step(&mut self, strategy: Strategy, caller: u64, registers: &Registers) -> Result<(StackFrame, Step, u64), Error> {
+        match strategy {
+            Strategy::Cfi => {
+                if let Some(&(range, ref hint)) = self.unwind_hints.iter().rev().find(|x| x.0.contains(caller)) {
+                    let cfa = registers.get(hint.cfa_register)
+                        .ok_or(Error::UndefinedRegister(hint.cfa_register))?
+                        .wrapping_add(hint.cfa_offset as u64);
+                    let frame = StackFrame {
+                        personality: None,
+                        lsda: None,
+                        initial_address: range.start,
+                        cfa,
+                        args_size: 0,
+                        signal_trampoline: false,
+                        trust: FrameTrust::Cfi,
+                    };
+                    return Ok((frame, Step::Hint(hint.clone()), cfa));
+                }
+                if !self.is_code_address(caller) {
+                    return Err(Error::NotCodeAddress(caller));
+                }
+                let UnwindInfo { row, personality, lsda, initial_address, signal_trampoline, .. } = self.unwind_info_for_address(caller)?;
+                trace!("ok: {:?} (0x{:x} - 0x{:x})", row.cfa(), row.start_address(), row.end_address());
+                let cfa = cfa_for_rule(row.cfa(), registers, expression::evaluate)?;
+                let frame = StackFrame {
+                    personality: personality.map(|x| unsafe { deref_ptr(x) }),
+                    lsda: lsda.map(|x| unsafe { deref_ptr(x) }),
+                    initial_address,
+                    cfa,
+                    args_size: row.saved_args_size(),
+                    signal_trampoline,
+                    trust: FrameTrust::Cfi,
+                };
+                Ok((frame, Step::Cfi(row), cfa))
+            }
+            Strategy::FramePointer => {
+                let rsp = registers[X86_64::RSP].ok_or(Error::UndefinedRegister(X86_64::RSP))?;
+                let rbp = registers[X86_64::RBP].ok_or(Error::UndefinedRegister(X86_64::RBP))?;
+                // saved rbp at [rbp], return address above it, both between the stack
+                // pointer and the end of the stack (rbp == rsp in frames without locals)
+                let cfa = rbp.wrapping_add(16);
+                if rbp == 0 || rbp % 8 != 0 || rbp < rsp || cfa < rbp || self.stack_end(rsp).map_or(false, |end| cfa > end) {
+                    return Err(Error::NotStackAddress(rbp));
+                }
+                let frame = StackFrame {
+                    personality: None,
+                    lsda: None,
+                    initial_address: 0,
+                    cfa,
+                    args_size: 0,
+                    signal_trampoline: false,
+                    trust: FrameTrust::FramePointer,
+                };
+                Ok((frame, Step::FramePointer, cfa))
+            }
+            Strategy::StackScan => {
+                let rsp = registers[X86_64::RSP].ok_or(Error::UndefinedRegister(X86_64::RSP))?;
+                // Without knowing where the stack ends, any word might be past it.
+                let end = self.stack_end(rsp).filter(|_| rsp % 8 == 0).ok_or(Error::NotStackAddress(rsp))?;
+                let words = std::cmp::min((end - rsp) / 8, SCAN_WORDS);
+                let slot = (0..words).map(|i| rsp.wrapping_add(i * 8))
+                    .find(|&slot| self.is_code_address(unsafe { *(slot as *const u64) }))
+                    .ok_or(Error::Gimli(gimli::Error::NoUnwindInfoForAddress))?;
+                let cfa = slot.wrapping_add(8);
+                let frame = StackFrame {
+                    personality: None,
+                    lsda: None,
+                    initial_address: 0,
+                    cfa,
+                    args_size: 0,
+                    signal_trampoline: false,
+                    trust: FrameTrust::Scanned,
+                };
+                Ok((frame, Step::Scan, cfa))
+            }
+        }
+    }
+
+    // A signal handler returns into the kernel's rt_sigreturn trampoline, with the
+    // interrupted context on top of the stack. Recognized by its code, so this works
+    // whether or not the trampoline has (correct) CFI.
+    fn sigreturn_frame(&self, ra: u64, registers: &Registers) -> Option<(StackFrame, Step, u64)> {
+        if !self.is_code_address(ra) || !self.is_code_address(ra + 8) || !unsafe { signal::is_sigreturn(ra) } {
+            return None;
+        }
+        let ucontext = registers[X86_64::RSP]?;
+        let cfa = unsafe { signal::restore(ucontext) }[X86_64::RSP]?;
+        let frame = StackFrame {
+            personality: None,
+            lsda: None,
+            initial_address: ra,
+            cfa,
+            args_size: 0,
+            signal_trampoline: true,
+            trust: FrameTrust::Signal,
+        };
+        Some((frame, Step::Sigreturn(ucontext), cfa))
+    }
+
+    // The end of the stack `rsp` is on: the thread's, or its signal stack in a handler.
+    fn stack_end(&self, rsp: u64) -> Option<u64> {
+        self.stack.filter(|stack| stack.contains(rsp))
+            .or_else(|| stack::alt_stack().filter(|stack| stack.contains(rsp)))
+            .map(|stack| stack.end)
+    }
+
+    /// Whether `address` lies in the executable segment of a loaded object, i.e. could be
+    /// a return address. Catches passing file offsets or data pointers where code
+    /// addresses are expected.
+    pub fn is_code_address(&self, address: u64) -> bool {
+        let registry_current = self.registry_current();
+        self.cfi.iter().any(|x| x.covers(address, registry_current))
+    }
+
+    // Whether nothing was deregistered since the unwinder was created.
+    fn registry_current(&self) -> bool {
+        registry::generation() == self.registry_generation
+    }
+
+    /// Shares evaluated unwind rows through `cache`, e.g. across the unwinders of several
+    /// threads of a profiler.
+    pub fn set_cache(&mut self, cache: Option<Arc<UnwindCache>>) {
+        self.cache = cache;
+    }
 
-                let bases = bases.set_eh_frame(eh_frame_addr);
+    /// Writes the return addresses of the calling thread's frames into `pcs` and returns
+    /// how many were written, without allocating, taking locks or logging, so it can run
+    /// in a signal handler. Symbolize them later, outside the handler.
+    ///
+    /// CFI is evaluated in place rather than through gimli's unwind tables, and
+    /// .debug_frame is only used if an earlier trace loaded it. The frame limit and the
+    /// skipped frames apply, but the shared cache, the stop condition and the context
+    /// switch hooks are left out, as they may allocate.
+    pub fn trace_into(&mut self, pcs: &mut [u64]) -> usize {
+        let mut len = 0;
+        glue::registers(|registers| len = self.trace_in_place(registers, pcs));
+        len
+    }
+
+    // `StackFrames::next_frame` minus everything that allocates or logs.
+    fn trace_in_place(&mut self, mut registers: Registers, pcs: &mut [u64]) -> usize {
+        let limit = self.max_frames.map_or(usize::MAX, |max| max.saturating_add(self.skip_frames));
+        let mut row = FixedRow::default();
+        let mut depth = 0;
+        let mut len = 0;
+        let mut interrupted = false;
+        let mut last_frame: Option<(u64, u64)> = None;
+        while depth < limit {
+            let ra = match registers[X86_64::RA] {
+                Some(ra) if ra != 0 => ra,
+                _ => break,
+            };
+            let caller = if interrupted { ra } else { ra.wrapping_sub(1) };
 
-                ObjectRecord { er, eh_frame_hdr, eh_frame, bases }
+            let mut found = if interrupted {
+                None
+            } else {
+                self.sigreturn_frame(ra, &registers).map(|(frame, step, cfa)| (Some(step), cfa, frame.signal_trampoline))
+            };
+            if found.is_none() {
+                let strategies = self.strategies_for(caller);
+                found = strategies.iter().find_map(|&strategy| self.step_in_place(strategy, caller, &registers, &mut row).ok());
             }
-        }).collect();
+            let (step, cfa, signal_trampoline) = match found {
+                Some(x) => x,
+                None => break,
+            };
 
-        DwarfUnwinder {
-            cfi,
-            ctx: UninitializedUnwindContext::new(),
+            if cfa == 0 || cfa % 8 != 0 {
+                break;
+            }
+            if last_frame == Some((cfa, caller)) || (!interrupted && last_frame.map_or(false, |(last, _)| cfa <= last)) {
+                break;
+            }
+            last_frame = Some((cfa, caller));
+            if depth >= self.skip_frames {
+                match pcs.get_mut(len) {
+                    Some(pc) => *pc = ra,
+                    None => break,
+                }
+                len += 1;
+            }
+            depth += 1;
+
+            let caller_registers = match step {
+                Some(step) => apply_step(step, cfa, &registers),
+                None => apply_rules(row.registers(), cfa, &registers, expression::evaluate_in_place),
+            };
+            registers = match caller_registers {
+                Ok(caller_registers) => caller_registers,
+                Err(_) => break,
+            };
+            interrupted = signal_trampoline;
+        }
+        len
+    }
+
+    // `step` for `trace_in_place`: CFI rows are evaluated into `row`, which is what the
+    // returned step is `None` for.
+    fn step_in_place(&mut self, strategy: Strategy, caller: u64, registers: &Registers, row: &mut FixedRow)
+                     -> Result<(Option<Step>, u64, bool), Error> {
+        if strategy != Strategy::Cfi || self.unwind_hints.iter().any(|x| x.0.contains(caller)) {
+            let (frame, step, cfa) = self.step(strategy, caller, registers)?;
+            return Ok((Some(step), cfa, frame.signal_trampoline));
+        }
+        if !self.is_code_address(caller) {
+            return Err(Error::NotCodeAddress(caller));
+        }
+        let registry_current = self.registry_current();
+        let mut result = Err(Error::Gimli(gimli::Error::NoUnwindInfoForAddress));
+        for rec in self.cfi.iter().rev().filter(|x| x.covers(caller, registry_current)) {
+            result = rec.fixed_row_for_address(caller, row);
+            match result {
+                Err(Error::Gimli(gimli::Error::NoUnwindInfoForAddress)) => (),
+                _ => break,
+            }
         }
+        let signal_trampoline = result?;
+        let cfa = cfa_for_rule(row.cfa()?, registers, expression::evaluate_in_place)?;
+        Ok((None, cfa, signal_trampoline))
+    }
+
+    fn unwind_info_for_address(&mut self, address: u64) -> gimli::Result<UnwindInfo<StaticReader>> {
+        if let Some(info) = self.cache.as_ref().and_then(|cache| cache.get(address)) {
+            return Ok(info);
+        }
+        // Registered blobs come last and may describe code inside a loaded object, so
+        // they are asked first.
+        let registry_current = self.registry_current();
+        let mut result = Err(gimli::Error::NoUnwindInfoForAddress);
+        let mut registered = false;
+        for rec in self.cfi.iter_mut().rev().filter(|x| x.covers(address, registry_current)) {
+            result = rec.unwind_info_for_address(&mut self.ctx, address);
+            registered = rec.registered;
+            match result {
+                Err(gimli::Error::NoUnwindInfoForAddress) => (),
+                _ => break,
+            }
+        }
+        let info = result?;
+        // The shared cache outlives this unwinder's view of the registry.
+        if let Some(cache) = self.cache.as_ref().filter(|_| !registered) {
+            cache.insert(info.range.start, info.range.end, info.clone());
+        }
+        Ok(info)
     }
 }
 
+impl private::Sealed for DwarfUnwinder {}
+
 impl Unwinder for DwarfUnwinder {
     fn trace<F>(&mut self, mut f: F) where F: FnMut(&mut StackFrames) {
         glue::registers(|registers| {
@@ -107,49 +625,213 @@ impl Unwinder for DwarfUnwinder {
     }
 }
 
+#[derive(Clone)]
 struct UnwindInfo<R: Reader> {
+    // The runtime addresses covered by `row`.
+    range: AddrRange,
     row: UnwindTableRow<R>,
     personality: Option<Pointer>,
     lsda: Option<Pointer>,
     initial_address: u64,
+    signal_trampoline: bool,
 }
 
 impl ObjectRecord {
+    unsafe fn new(er: EhRef) -> ObjectRecord {
+        // TODO: set_got()
+        let mut bases = BaseAddresses::default()
+            .set_text(er.text.start);
+
+        let mut eh_frame_hdr = None;
+        let mut eh_frame_range = None;
+        if let Some(hdr) = er.eh_frame_hdr {
+            bases = bases.set_eh_frame_hdr(hdr.start);
+
+            let data: &'static [u8] = std::slice::from_raw_parts(hdr.start as *const u8, hdr.len() as usize);
+
+            match EhFrameHdr::new(data, NativeEndian).parse(&bases, 8) {
+                Ok(parsed) => {
+                    let eh_frame_addr = deref_ptr(parsed.eh_frame_ptr());
+                    // This is an upper bound, not the exact end.
+                    let eh_frame_end = std::cmp::max(er.eh_frame_end, eh_frame_addr);
+                    eh_frame_range = Some(AddrRange { start: eh_frame_addr, end: eh_frame_end });
+                    eh_frame_hdr = Some(parsed);
+                }
+                Err(e) => debug!("bad eh_frame_hdr at {:p}: {}", hdr.start as *const u8, e),
+            }
+        }
+        if eh_frame_range.is_none() {
+            eh_frame_range = find_cfi::eh_frame_section(&er);
+        }
+
+        let eh_frame = match eh_frame_range {
+            Some(range) => {
+                let eh_frame: &'static [u8] = std::slice::from_raw_parts(range.start as *const u8, range.len() as usize);
+                trace!("eh_frame at {:p} sz {:x}", range.start as *const u8, range.len());
+                let eh_frame = EhFrame::new(eh_frame, NativeEndian);
+
+                bases = bases.set_eh_frame(range.start);
+
+                let has_table = eh_frame_hdr.as_ref().map_or(false, |hdr| hdr.table().is_some());
+                let index = match eh_frame_hdr {
+                    Some(hdr) if has_table => FdeIndex::Hdr(hdr),
+                    _ => FdeIndex::Sorted(FdeTable::new(&eh_frame, &bases)),
+                };
+                Some((eh_frame, index))
+            }
+            None => None,
+        };
+
+        ObjectRecord { er, eh_frame, debug_frame: None, bases, registered: false }
+    }
+
+    // A blob given to `register_frame` or announced through the GDB JIT interface,
+    // covering whatever code its FDEs describe.
+    unsafe fn from_eh_frame(range: AddrRange) -> Option<ObjectRecord> {
+        let data: &'static [u8] = std::slice::from_raw_parts(range.start as *const u8, range.len() as usize);
+        let eh_frame = EhFrame::new(data, NativeEndian);
+        let bases = BaseAddresses::default().set_eh_frame(range.start);
+        let table = FdeTable::new(&eh_frame, &bases);
+        let text = match table.range() {
+            Some(text) => text,
+            None => {
+                debug!("no FDEs in registered eh_frame at {:p}", range.start as *const u8);
+                return None;
+            }
+        };
+        trace!("registered eh_frame at {:p} covers {:?}", range.start as *const u8, text);
+
+        let er = EhRef {
+            text,
+            eh_frame_hdr: None,
+            eh_frame_end: range.end,
+            bias: 0,
+            path: None,
+            build_id: None,
+        };
+        // there is no file to load .debug_frame from
+        Some(ObjectRecord {
+            er,
+            eh_frame: Some((eh_frame, FdeIndex::Sorted(table))),
+            debug_frame: Some(None),
+            bases,
+            registered: false,
+        })
+    }
+
+    // Registered blobs may be freed once deregistered, so their records are left out as
+    // soon as anything was.
+    fn covers(&self, address: u64, registry_current: bool) -> bool {
+        (registry_current || !self.registered) && self.er.text.contains(address)
+    }
+
+    fn eh_frame_fde(&self, address: u64) -> gimli::Result<(&EhFrame<StaticReader>, FrameDescriptionEntry<StaticReader>)> {
+        let (eh_frame, index) = self.eh_frame.as_ref().ok_or(gimli::Error::NoUnwindInfoForAddress)?;
+        let fde = match *index {
+            FdeIndex::Hdr(ref hdr) => match hdr.table() {
+                Some(table) => table.fde_for_address(eh_frame, &self.bases, address, EhFrame::cie_from_offset),
+                None => Err(gimli::Error::NoUnwindInfoForAddress),
+            },
+            FdeIndex::Sorted(ref table) => table.fde_for_address(address),
+        }?;
+        Ok((eh_frame, fde))
+    }
+
     fn unwind_info_for_address(
-        &self,
+        &mut self,
         ctx: &mut UninitializedUnwindContext<StaticReader>,
         address: u64,
     ) -> gimli::Result<UnwindInfo<StaticReader>> {
-        let &ObjectRecord {
-            ref eh_frame_hdr,
-            ref eh_frame,
-            ref bases,
-            ..
-        } = self;
-
-        let fde = eh_frame_hdr.table().unwrap()
-            .fde_for_address(eh_frame, bases, address, EhFrame::cie_from_offset)?;
-        let mut result_row = None;
-        {
-            let mut table = UnwindTable::new(eh_frame, bases, ctx, &fde)?;
-            while let Some(row) = table.next_row()? {
-                if row.contains(address) {
-                    result_row = Some(row.clone());
-                    break;
-                }
+        match self.eh_frame_fde(address) {
+            Ok((eh_frame, fde)) => return unwind_info_from_fde(eh_frame, &self.bases, ctx, &fde, address, 0),
+            Err(gimli::Error::NoUnwindInfoForAddress) => (),
+            Err(e) => return Err(e),
+        }
+
+        // .debug_frame holds link-time addresses, so look it up without the load bias.
+        let bias = self.er.bias;
+        let bases = BaseAddresses::default();
+        match self.debug_frame() {
+            Some((debug_frame, table)) => {
+                let fde = table.fde_for_address(address.wrapping_sub(bias))?;
+                unwind_info_from_fde(debug_frame, &bases, ctx, &fde, address.wrapping_sub(bias), bias)
+            }
+            None => Err(gimli::Error::NoUnwindInfoForAddress),
+        }
+    }
+
+    // Like `unwind_info_for_address`, but only with a .debug_frame that is loaded already.
+    // Returns whether the frame is a signal trampoline.
+    fn fixed_row_for_address(&self, address: u64, row: &mut FixedRow) -> Result<bool, Error> {
+        match self.eh_frame_fde(address) {
+            Ok((eh_frame, fde)) => {
+                row.evaluate(eh_frame, &self.bases, &fde, address)?;
+                return Ok(fde.is_signal_trampoline());
+            }
+            Err(gimli::Error::NoUnwindInfoForAddress) => (),
+            Err(e) => return Err(e.into()),
+        }
+
+        match self.debug_frame {
+            Some(Some((_, ref debug_frame, ref table))) => {
+                let address = address.wrapping_sub(self.er.bias);
+                let fde = table.fde_for_address(address)?;
+                row.evaluate(debug_frame, &BaseAddresses::default(), &fde, address)?;
+                Ok(fde.is_signal_trampoline())
             }
+            _ => Err(Error::Gimli(gimli::Error::NoUnwindInfoForAddress)),
         }
+    }
 
-        match result_row {
-            Some(row) => Ok(UnwindInfo {
-                row,
-                personality: fde.personality(),
-                lsda: fde.lsda(),
-                initial_address: fde.initial_address(),
-            }),
-            None => Err(gimli::Error::NoUnwindInfoForAddress)
+    fn debug_frame(&mut self) -> Option<(&DebugFrame<StaticReader>, &FdeTable)> {
+        if self.debug_frame.is_none() {
+            let loaded = find_cfi::load_debug_frame(&self.er).map(|data| {
+                // The boxed data lives (and stays put) as long as the record does.
+                let slice: &'static [u8] = unsafe { &*(&*data as *const [u8]) };
+                trace!("loaded .debug_frame for {:?} sz {:x}", self.er.text, slice.len());
+                let debug_frame = DebugFrame::new(slice, NativeEndian);
+                let table = FdeTable::new(&debug_frame, &BaseAddresses::default());
+                (data, debug_frame, table)
+            });
+            self.debug_frame = Some(loaded);
+        }
+        self.debug_frame.as_ref().and_then(|x| x.as_ref()).map(|x| (&x.1, &x.2))
+    }
+}
+
+fn unwind_info_from_fde<S: UnwindSection<StaticReader>>(
+    section: &S,
+    bases: &BaseAddresses,
+    ctx: &mut UninitializedUnwindContext<StaticReader>,
+    fde: &FrameDescriptionEntry<StaticReader>,
+    address: u64,
+    bias: u64,
+) -> gimli::Result<UnwindInfo<StaticReader>> {
+    let mut result_row = None;
+    {
+        let mut table = UnwindTable::new(section, bases, ctx, fde)?;
+        while let Some(row) = table.next_row()? {
+            if row.contains(address) {
+                result_row = Some(row.clone());
+                break;
+            }
         }
     }
+
+    match result_row {
+        Some(row) => Ok(UnwindInfo {
+            range: AddrRange {
+                start: row.start_address().wrapping_add(bias),
+                end: row.end_address().wrapping_add(bias),
+            },
+            row,
+            personality: fde.personality(),
+            lsda: fde.lsda(),
+            initial_address: fde.initial_address().wrapping_add(bias),
+            signal_trampoline: fde.is_signal_trampoline(),
+        }),
+        None => Err(gimli::Error::NoUnwindInfoForAddress)
+    }
 }
 
 unsafe fn deref_ptr(ptr: Pointer) -> u64 {
@@ -166,70 +848,316 @@ impl<'a> StackFrames<'a> {
             unwinder,
             registers,
             state: None,
+            depth: 0,
+            last_frame: None,
+            stop_reason: None,
+            interrupted: false,
+            returned_interrupted: false,
         }
     }
 
+    /// Starts from the registers of an interrupted thread (see `Registers::from_ucontext`),
+    /// whose return address column holds the interrupted instruction itself.
+    pub fn from_interrupted(unwinder: &'a mut DwarfUnwinder, registers: Registers) -> Self {
+        StackFrames { interrupted: true, ..StackFrames::new(unwinder, registers) }
+    }
+
     pub fn registers(&mut self) -> &mut Registers {
         &mut self.registers
     }
+
+    /// The canonical frame address of the frame last returned by `next()`, 0 before the first one.
+    pub fn cfa(&self) -> u64 {
+        self.state.as_ref().map_or(0, |&(_, cfa, _)| cfa)
+    }
+
+    /// How the frame last returned by `next()` saved its caller's registers. Registers that
+    /// aren't listed keep their value, except the stack pointer, which becomes the CFA.
+    pub fn register_rules(&self) -> impl Iterator<Item = (Register, RegisterRecovery)> + '_ {
+        static FRAME_POINTER: [(Register, RegisterRecovery); 2] =
+            [(X86_64::RBP, RegisterRecovery::Offset(-16)), (X86_64::RA, RegisterRecovery::Offset(-8))];
+        static RETURN_ADDRESS: [(Register, RegisterRecovery); 1] = [(X86_64::RA, RegisterRecovery::Offset(-8))];
+
+        let step = self.state.as_ref().map(|&(ref step, _, _)| step);
+        let cfi = match step {
+            Some(Step::Cfi(row)) => Some(row.registers().map(|&(reg, ref rule)| (reg, match *rule {
+                RegisterRule::Undefined => RegisterRecovery::Undefined,
+                RegisterRule::SameValue => RegisterRecovery::SameValue,
+                RegisterRule::Offset(n) => RegisterRecovery::Offset(n),
+                RegisterRule::ValOffset(n) => RegisterRecovery::ValOffset(n),
+                RegisterRule::Register(r) => RegisterRecovery::Register(r.0),
+                RegisterRule::Expression(_) => RegisterRecovery::Expression,
+                RegisterRule::ValExpression(_) => RegisterRecovery::ValExpression,
+                RegisterRule::Architectural => RegisterRecovery::Architectural,
+            }))),
+            _ => None,
+        };
+        let hint = match step {
+            Some(Step::Hint(hint)) => Some(hint.saved.iter().map(|&(reg, n)| (reg, RegisterRecovery::Offset(n)))),
+            _ => None,
+        };
+        let fixed: &'static [(Register, RegisterRecovery)] = match step {
+            Some(Step::FramePointer) => &FRAME_POINTER,
+            Some(Step::Scan) => &RETURN_ADDRESS,
+            Some(Step::Hint(hint)) if !hint.saved.iter().any(|x| x.0 == X86_64::RA) => &RETURN_ADDRESS,
+            _ => &[],
+        };
+        // all of them are read from the ucontext
+        let sigreturn = match step {
+            Some(Step::Sigreturn(_)) => 0..registers::REGISTER_COUNT as u16,
+            _ => 0..0,
+        };
+
+        cfi.into_iter().flatten()
+            .chain(hint.into_iter().flatten())
+            .chain(fixed.iter().copied())
+            .chain(sigreturn.map(|reg| (Register(reg), RegisterRecovery::Expression)))
+    }
+
+    /// Whether the frame last returned by `next()` was interrupted, e.g. by a signal,
+    /// rather than calling its callee. Its return address column then holds the
+    /// interrupted instruction, not the one after a call.
+    pub fn is_interrupted(&self) -> bool {
+        self.returned_interrupted
+    }
+
+    /// Why iteration ended, once `next()` has returned `Ok(None)`.
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    fn stop(&mut self, reason: StopReason) -> Result<Option<StackFrame>, Error> {
+        debug!("stopping: {:?}", reason);
+        self.stop_reason = Some(reason);
+        Ok(None)
+    }
 }
 
 impl<'a> FallibleIterator for StackFrames<'a> {
     type Item = StackFrame;
-    type Error = gimli::Error;
+    type Error = Error;
 
     fn next(&mut self) -> Result<Option<StackFrame>, Self::Error> {
-        let registers = &mut self.registers;
-
-        if let Some((row, cfa)) = self.state.take() {
-            let mut newregs = registers.clone();
-            newregs[X86_64::RA] = None;
-            for &(reg, ref rule) in row.registers() {
-                trace!("rule {:?} {:?}", reg, rule);
-                assert!(reg != X86_64::RSP); // stack = cfa
-                newregs[reg] = match *rule {
-                    RegisterRule::Undefined => unreachable!(), // registers[reg],
-                    RegisterRule::SameValue => Some(registers[reg].unwrap()), // not sure why this exists
-                    RegisterRule::Register(r) => registers[r],
-                    RegisterRule::Offset(n) => Some(unsafe { *((cfa.wrapping_add(n as u64)) as *const u64) }),
-                    RegisterRule::ValOffset(n) => Some(cfa.wrapping_add(n as u64)),
-                    RegisterRule::Expression(_) => unimplemented!(),
-                    RegisterRule::ValExpression(_) => unimplemented!(),
-                    RegisterRule::Architectural => unreachable!(),
-                };
+        while self.depth < self.unwinder.skip_frames {
+            if self.next_frame()?.is_none() {
+                return Ok(None);
             }
-            newregs[7] = Some(cfa);
+        }
+        self.next_frame()
+    }
+}
 
-            *registers = newregs;
-            trace!("registers:{:?}", registers);
+impl<'a> StackFrames<'a> {
+    fn next_frame(&mut self) -> Result<Option<StackFrame>, Error> {
+        if self.stop_reason.is_some() {
+            return Ok(None);
+        }
+        // skipped frames don't count towards the limit
+        if Some(self.depth.saturating_sub(self.unwinder.skip_frames)) == self.unwinder.max_frames {
+            return self.stop(StopReason::MaxDepth);
         }
 
+        let registers = &mut self.registers;
 
-        if let Some(mut caller) = registers[X86_64::RA] {
-            caller -= 1; // THIS IS NECESSARY
-            debug!("caller is 0x{:x}", caller);
+        let mut interrupted = ::std::mem::replace(&mut self.interrupted, false);
+        let mut stack_switched = false;
+        if let Some((step, cfa, signal_trampoline)) = self.state.take() {
+            *registers = apply_step(step, cfa, registers)?;
+            interrupted = signal_trampoline;
+            trace!("registers:{:?}", registers);
+        }
 
-            let rec = self.unwinder.cfi.iter().filter(|x| x.er.text.contains(caller)).next().ok_or(gimli::Error::NoUnwindInfoForAddress)?;
+        if let Some(pc) = registers[X86_64::RA] {
+            if let Some(&(_, ref hook)) = self.unwinder.context_switches.iter().find(|x| x.0.contains(pc)) {
+                if let Some(switched) = hook(&*registers) {
+                    debug!("context switch at 0x{:x}", pc);
+                    *registers = switched;
+                    interrupted = false;
+                    stack_switched = true;
+                }
+            }
+        }
 
-            let UnwindInfo { row, personality, lsda, initial_address } = rec.unwind_info_for_address(&mut self.unwinder.ctx, caller)?;
+        // Entry points mark the outermost frame with an undefined return address in their
+        // CFI, some runtimes with a zero one.
+        if let Some(ra) = registers[X86_64::RA].filter(|&ra| ra != 0) {
+            let mut caller = ra;
+            // A signal trampoline "returns" to the interrupted instruction, not past a call.
+            if !interrupted {
+                caller = caller.wrapping_sub(1); // THIS IS NECESSARY
+            }
+            debug!("caller is 0x{:x}", caller);
 
-            trace!("ok: {:?} (0x{:x} - 0x{:x})", row.cfa(), row.start_address(), row.end_address());
-            let cfa = match *row.cfa() {
-                CfaRule::RegisterAndOffset { register, offset } =>
-                    registers[register].unwrap().wrapping_add(offset as u64),
-                _ => unimplemented!(),
+            // Only a handler returning calls into the trampoline; being interrupted there doesn't.
+            let mut found = if interrupted { None } else { self.unwinder.sigreturn_frame(ra, registers) };
+            let mut first_error = None;
+            for &strategy in self.unwinder.strategies_for(caller).iter() {
+                if found.is_some() {
+                    break;
+                }
+                match self.unwinder.step(strategy, caller, registers) {
+                    Ok(x) => {
+                        found = Some(x);
+                        break;
+                    }
+                    Err(e) => {
+                        debug!("{:?} failed for 0x{:x}: {}", strategy, caller, e);
+                        first_error.get_or_insert(e);
+                    }
+                }
+            }
+            let (frame, step, cfa) = match found {
+                Some(x) => x,
+                None => return Err(first_error.unwrap_or(Error::Gimli(gimli::Error::NoUnwindInfoForAddress))),
             };
             trace!("cfa is 0x{:x}", cfa);
 
-            self.state = Some((row, cfa));
+            if cfa == 0 || cfa % 8 != 0 {
+                return self.stop(StopReason::BadCfa);
+            }
+            // Signal handlers and context switches may run on a different stack, but no
+            // frame may repeat the previous one exactly.
+            if self.last_frame == Some((cfa, caller))
+                || (!interrupted && !stack_switched && self.last_frame.map_or(false, |(last, _)| cfa <= last)) {
+                return self.stop(StopReason::Cycle);
+            }
+            if let Some(ref condition) = self.unwinder.stop_condition {
+                if condition(&frame, registers) == Control::Stop {
+                    return self.stop(StopReason::Requested);
+                }
+            }
+            self.last_frame = Some((cfa, caller));
+            self.depth += 1;
+
+            self.state = Some((step, cfa, frame.signal_trampoline));
+            self.returned_interrupted = interrupted;
 
-            Ok(Some(StackFrame {
-                personality: personality.map(|x| unsafe { deref_ptr(x) }),
-                lsda: lsda.map(|x| unsafe { deref_ptr(x) }),
-                initial_address,
-            }))
+            Ok(Some(frame))
         } else {
-            Ok(None)
+            self.stop(StopReason::EndOfStack)
+        }
+    }
+}
+
+// `expression::evaluate` or `expression::evaluate_in_place`
+type Evaluate = fn(&gimli::Expression<StaticReader>, &Registers, Option<u64>) -> Result<u64, Error>;
+
+fn cfa_for_rule(rule: &CfaRule<StaticReader>, registers: &Registers, evaluate: Evaluate) -> Result<u64, Error> {
+    Ok(match *rule {
+        CfaRule::RegisterAndOffset { register, offset } =>
+            registers.get(register).ok_or(Error::UndefinedRegister(register))?.wrapping_add(offset as u64),
+        CfaRule::Expression(ref expr) => evaluate(expr, registers, None)?,
+    })
+}
+
+// Clobbered by calls in the SysV ABI, so unknown in the caller unless a rule restores
+// them (as in signal frames). Everything else is callee-saved and carries over.
+const CALLER_SAVED: [gimli::Register; 9] = [
+    X86_64::RAX, X86_64::RCX, X86_64::RDX, X86_64::RSI, X86_64::RDI,
+    X86_64::R8, X86_64::R9, X86_64::R10, X86_64::R11,
+];
+
+// Computes the caller's registers from the frame's, given how to step past the frame.
+fn apply_step(step: Step, cfa: u64, registers: &Registers) -> Result<Registers, Error> {
+    Ok(match step {
+        Step::Cfi(row) => {
+            for &(reg, ref rule) in row.registers() {
+                trace!("rule {:?} {:?}", reg, rule);
+            }
+            apply_rules(row.registers().map(|&(reg, ref rule)| (reg, rule)), cfa, registers, expression::evaluate)?
+        }
+        Step::FramePointer => apply_frame_pointer(cfa, registers),
+        Step::Scan => apply_scan(cfa, registers),
+        Step::Hint(hint) => apply_hint(&hint, cfa, registers),
+        Step::Sigreturn(ucontext) => unsafe { signal::restore(ucontext) },
+    })
+}
+
+fn apply_rules<'a, I>(rules: I, cfa: u64, registers: &Registers, evaluate: Evaluate) -> Result<Registers, Error>
+    where I: Iterator<Item = (gimli::Register, &'a RegisterRule<StaticReader>)>
+{
+    let mut newregs = registers.clone();
+    for &reg in &CALLER_SAVED {
+        newregs[reg] = None;
+    }
+    #[cfg(feature = "vector_registers")]
+    newregs.clear_vector();
+    newregs[X86_64::RA] = None;
+    // stack = cfa, unless a rule says otherwise (signal frames restore it from the ucontext)
+    newregs[X86_64::RSP] = Some(cfa);
+    for (reg, rule) in rules {
+        #[cfg(feature = "vector_registers")]
+        {
+            if Registers::tracks_vector(reg) {
+                let value = match *rule {
+                    RegisterRule::SameValue => registers.vector(reg),
+                    RegisterRule::Register(r) => registers.vector(r),
+                    RegisterRule::Offset(n) => Some(unsafe { ::std::ptr::read_unaligned(cfa.wrapping_add(n as u64) as *const u128) }),
+                    RegisterRule::Expression(ref expr) => {
+                        let addr = evaluate(expr, registers, Some(cfa))?;
+                        Some(unsafe { ::std::ptr::read_unaligned(addr as *const u128) })
+                    }
+                    // no 128-bit values to compute
+                    _ => None,
+                };
+                newregs.set_vector(reg, value);
+                continue;
+            }
+        }
+        if !Registers::tracks(reg) {
+            continue;
+        }
+        newregs[reg] = match *rule {
+            RegisterRule::Undefined => None,
+            RegisterRule::SameValue => registers[reg],
+            RegisterRule::Register(r) => registers.get(r),
+            RegisterRule::Offset(n) => Some(unsafe { *((cfa.wrapping_add(n as u64)) as *const u64) }),
+            RegisterRule::ValOffset(n) => Some(cfa.wrapping_add(n as u64)),
+            RegisterRule::Expression(ref expr) => {
+                let addr = evaluate(expr, registers, Some(cfa))?;
+                Some(unsafe { *(addr as *const u64) })
+            }
+            RegisterRule::ValExpression(ref expr) => Some(evaluate(expr, registers, Some(cfa))?),
+            // x86_64 defines no architectural rules
+            RegisterRule::Architectural => None,
+        };
+    }
+    Ok(newregs)
+}
+
+fn apply_frame_pointer(cfa: u64, registers: &Registers) -> Registers {
+    let mut newregs = registers.clone();
+    for &reg in &CALLER_SAVED {
+        newregs[reg] = None;
+    }
+    #[cfg(feature = "vector_registers")]
+    newregs.clear_vector();
+    unsafe {
+        newregs[X86_64::RBP] = Some(*(cfa.wrapping_sub(16) as *const u64));
+        newregs[X86_64::RA] = Some(*(cfa.wrapping_sub(8) as *const u64));
+    }
+    newregs[X86_64::RSP] = Some(cfa);
+    newregs
+}
+
+fn apply_scan(cfa: u64, registers: &Registers) -> Registers {
+    let mut newregs = registers.clone();
+    for &reg in &CALLER_SAVED {
+        newregs[reg] = None;
+    }
+    #[cfg(feature = "vector_registers")]
+    newregs.clear_vector();
+    newregs[X86_64::RA] = Some(unsafe { *(cfa.wrapping_sub(8) as *const u64) });
+    newregs[X86_64::RSP] = Some(cfa);
+    newregs
+}
+
+fn apply_hint(hint: &UnwindHint, cfa: u64, registers: &Registers) -> Registers {
+    let mut newregs = apply_scan(cfa, registers);
+    for &(reg, offset) in &hint.saved {
+        if Registers::tracks(reg) {
+            newregs[reg] = Some(unsafe { *(cfa.wrapping_add(offset as u64) as *const u64) });
         }
     }
+    newregs
 }
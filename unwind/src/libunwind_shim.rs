@@ -1,11 +1,24 @@
+//! The `_Unwind_*` interface of libgcc_s and LLVM's libunwind, implemented on top of
+//! `DwarfUnwinder`.
+//!
+//! Rust's std already links an unwinder exporting the same symbols: libgcc_s on
+//! linux-gnu, a bundled copy of LLVM's libunwind on musl. Definitions in the executable
+//! (this module, linked statically) interpose those of libgcc_s, so std's own panics go
+//! through this shim as well; statically linking it next to the bundled libunwind makes
+//! for duplicate symbols. Only enable it in programs where it can be the one unwinder
+//! for every personality routine, e.g. C and C++ runtimes or `panic = "abort"` builds.
 #![allow(non_camel_case_types, non_snake_case, unused_variables)]
 
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
 use libc::{c_void, c_int};
 use fallible_iterator::FallibleIterator;
 use gimli::X86_64;
 
 use registers::Registers;
-use super::{DwarfUnwinder, Unwinder, StackFrames};
+use super::{DwarfUnwinder, Unwinder, StackFrames, StackFrame};
+use crate::{find_cfi, registry};
 
 #[repr(C)]
 #[derive(Copy, Clone, PartialEq)]
@@ -48,26 +61,126 @@ pub struct _Unwind_Context {
     pub lsda: u64,
     pub ip: u64,
     pub initial_address: u64,
+    pub cfa: u64,
+    pub args_size: u64,
     pub registers: *mut Registers,
+    /// `ip` is an interrupted instruction rather than a return address.
+    pub interrupted: bool,
 }
 pub type _Unwind_Trace_Fn = extern "C" fn(ctx: *mut _Unwind_Context, arg: *mut c_void)
                                           -> _Unwind_Reason_Code;
 type PersonalityRoutine = extern "C" fn(version: c_int, actions: c_int, class: u64, object: *mut _Unwind_Exception, context: *mut _Unwind_Context) -> _Unwind_Reason_Code;
 
-// FIXME: we skip over this function when unwinding, so we should ensure
-// it never needs any cleanup. Currently this is not true.
+pub type _Unwind_Stop_Fn = extern "C" fn(version: c_int, actions: c_int, class: u64, object: *mut _Unwind_Exception,
+                                         context: *mut _Unwind_Context, stop_argument: *mut c_void) -> _Unwind_Reason_Code;
+
+// How phase 2 treats an exception in flight. This has to survive the landing pads
+// calling _Unwind_Resume, and the exception header has no room left for it.
+#[derive(Clone, Copy)]
+enum Cleanup {
+    // the CFA of the frame phase 1 found a handler in
+    UntilHandler(u64),
+    Forced(_Unwind_Stop_Fn, *mut c_void),
+}
+
+thread_local! {
+    static IN_FLIGHT: RefCell<Vec<(*mut _Unwind_Exception, Cleanup)>> = RefCell::new(Vec::new());
+}
+
+// What the unwinders below were created from: the loaded objects, deregistrations and
+// registrations.
+type Snapshot = (Option<u64>, usize, usize);
+
+// Backtrace-style callers ask for the enclosing function of every frame, each time
+// through a new unwinder otherwise. They do so from inside _Unwind_Backtrace, so the
+// two don't share one.
+thread_local! {
+    static BACKTRACE_UNWINDER: RefCell<Option<(Snapshot, DwarfUnwinder)>> = RefCell::new(None);
+    static LOOKUP_UNWINDER: RefCell<Option<(Snapshot, DwarfUnwinder)>> = RefCell::new(None);
+}
+
+// Runs `f` with the unwinder cached in `slot`, replacing it if objects or blobs came or
+// went since it was created.
+fn with_unwinder<R, F>(slot: &'static LocalKey<RefCell<Option<(Snapshot, DwarfUnwinder)>>>, f: F) -> R
+    where F: FnOnce(&mut DwarfUnwinder) -> R
+{
+    let snapshot = (find_cfi::loaded_generation(), registry::generation(), registry::registrations());
+    slot.with(|cached| match cached.try_borrow_mut() {
+        Ok(mut cached) => {
+            // without the loader's counters, nothing tells when it is out of date
+            if snapshot.0.is_none() || cached.as_ref().map_or(false, |x| x.0 != snapshot) {
+                *cached = None;
+            }
+            let (_, unwinder) = cached.get_or_insert_with(|| (snapshot, DwarfUnwinder::default()));
+            f(unwinder)
+        }
+        // called again from inside `f`
+        Err(_) => f(&mut DwarfUnwinder::default()),
+    })
+}
+
+fn in_flight(exception: *mut _Unwind_Exception) -> Option<Cleanup> {
+    IN_FLIGHT.with(|x| x.borrow().iter().find(|x| x.0 == exception).map(|x| x.1))
+}
+
+fn set_in_flight(exception: *mut _Unwind_Exception, cleanup: Option<Cleanup>) {
+    IN_FLIGHT.with(|x| {
+        let mut x = x.borrow_mut();
+        x.retain(|x| x.0 != exception);
+        if let Some(cleanup) = cleanup {
+            x.push((exception, cleanup));
+        }
+    });
+}
+
+// The entry points skip their own frames and those of the functions they call: the
+// personality routines of Rust frames would otherwise see a foreign exception in a
+// call that can't unwind, and abort. `entry` is the address of a local of the entry
+// point; whatever it calls has its CFA below that.
+macro_rules! entry_marker {
+    () => {{
+        let marker = 0u8;
+        &marker as *const u8 as u64
+    }};
+}
+
+// Skips frames up to and including that of the entry point holding `entry`.
+fn skip_own_frames(frames: &mut StackFrames, entry: u64) -> Result<(), crate::Error> {
+    while frames.next()?.is_some() {
+        if frames.cfa() > entry {
+            break;
+        }
+    }
+    Ok(())
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_Resume(exception: *mut _Unwind_Exception) -> ! {
+    resume(exception, entry_marker!())
+}
+
+unsafe fn resume(exception: *mut _Unwind_Exception, entry: u64) -> ! {
+    let cleanup = in_flight(exception);
     ::glue::registers(|registers| {
-        if let Some(registers) = unwind_tracer(registers, exception) {
-            ::glue::land(&registers);
-        }
+        let code = land(cleanup_phase(registers, exception, cleanup, entry));
+        error!("resuming unwind failed: {:?}", code);
     });
-    unreachable!();
+    // There is nobody to return to.
+    ::std::process::abort();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_Resume_or_Rethrow(exception: *mut _Unwind_Exception) -> _Unwind_Reason_Code {
+    let entry = entry_marker!();
+    match in_flight(exception) {
+        Some(Cleanup::Forced(..)) => resume(exception, entry),
+        _ => raise(exception, entry),
+    }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_DeleteException(exception: *mut _Unwind_Exception) {
+    set_in_flight(exception, None);
     ((*exception).exception_cleanup)(_Unwind_Reason_Code::_URC_FOREIGN_EXCEPTION_CAUGHT, exception);
     trace!("exception deleted.");
 }
@@ -79,12 +192,12 @@ pub unsafe extern "C" fn _Unwind_GetRegionStart(ctx: *mut _Unwind_Context) -> _U
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_GetTextRelBase(ctx: *mut _Unwind_Context) -> _Unwind_Ptr {
-    unreachable!();
+    0 // not used with x86_64 pointer encodings
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_GetDataRelBase(ctx: *mut _Unwind_Context) -> _Unwind_Ptr {
-    unreachable!();
+    0 // not used with x86_64 pointer encodings
 }
 
 #[no_mangle]
@@ -92,26 +205,80 @@ pub unsafe extern "C" fn _Unwind_GetLanguageSpecificData(ctx: *mut _Unwind_Conte
     (*ctx).lsda as *mut c_void
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetCFA(ctx: *mut _Unwind_Context) -> _Unwind_Word {
+    (*ctx).cfa as usize
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetGR(ctx: *mut _Unwind_Context, reg_index: c_int) -> _Unwind_Word {
+    (*(*ctx).registers).get(gimli::Register(reg_index as u16)).unwrap_or(0) as usize
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_SetGR(ctx: *mut _Unwind_Context, reg_index: c_int, value: _Unwind_Word) {
-    (*(*ctx).registers)[reg_index as u16] = Some(value as u64);
+    let reg = gimli::Register(reg_index as u16);
+    if Registers::tracks(reg) {
+        (*(*ctx).registers)[reg] = Some(value as u64);
+    }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_SetIP(ctx: *mut _Unwind_Context, value: _Unwind_Word) {
     (*(*ctx).registers)[X86_64::RA] = Some(value as u64);
+    // The landing pad expects the arguments pushed for the call to be gone.
+    if let Some(ref mut rsp) = (*(*ctx).registers)[X86_64::RSP] {
+        *rsp += (*ctx).args_size;
+    }
+    (*ctx).args_size = 0;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_GetIP(ctx: *mut _Unwind_Context) -> _Unwind_Word {
+    (*ctx).ip as usize
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_GetIPInfo(ctx: *mut _Unwind_Context, ip_before_insn: *mut c_int)
                                     -> _Unwind_Word {
-    *ip_before_insn = 0;
+    *ip_before_insn = (*ctx).interrupted as c_int;
     (*ctx).ip as usize
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_FindEnclosingFunction(pc: *mut c_void) -> *mut c_void {
-    pc // FIXME: implement this
+    match with_unwinder(&LOOKUP_UNWINDER, |unwinder| unwinder.unwind_info_for_address(pc as u64)) {
+        Ok(info) => info.initial_address as *mut c_void,
+        Err(_) => ::std::ptr::null_mut(),
+    }
+}
+
+// libgcc's interface: `begin` is a whole .eh_frame section, ending with a zero length.
+#[no_mangle]
+pub unsafe extern "C" fn __register_frame(begin: *const c_void) {
+    let len = eh_frame_len(begin as *const u8);
+    if len != 0 {
+        crate::register_frame(::std::slice::from_raw_parts(begin as *const u8, len));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn __deregister_frame(begin: *const c_void) {
+    // libgcc has the same contract
+    crate::deregister_frame(begin as *const u8);
+}
+
+// Walks the entries up to the terminator, which isn't included.
+unsafe fn eh_frame_len(begin: *const u8) -> usize {
+    let mut entry = begin;
+    loop {
+        let len = ::std::ptr::read_unaligned(entry as *const u32);
+        entry = match len {
+            0 => return entry as usize - begin as usize,
+            0xffff_ffff => entry.add(12 + ::std::ptr::read_unaligned(entry.add(4) as *const u64) as usize),
+            len => entry.add(4 + len as usize),
+        };
+    }
 }
 
 // FIXME: Set `unwind(allowed)` because we need to be able to unwind this function as
@@ -123,72 +290,217 @@ pub unsafe extern "C" fn _Unwind_FindEnclosingFunction(pc: *mut c_void) -> *mut
 #[cfg_attr(feature = "nightly", unwind(allowed))]
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_RaiseException(exception: *mut _Unwind_Exception) -> _Unwind_Reason_Code {
+    raise(exception, entry_marker!())
+}
+
+unsafe fn raise(exception: *mut _Unwind_Exception, entry: u64) -> _Unwind_Reason_Code {
     (*exception).private_contptr = None;
+    let mut code = _Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR;
     ::glue::registers(|registers| {
-        if let Some(registers) = unwind_tracer(registers, exception) {
-            ::glue::land(&registers);
-        }
+        code = match search_phase(registers.clone(), exception, entry) {
+            Ok(handler) => {
+                let cleanup = Cleanup::UntilHandler(handler);
+                set_in_flight(exception, Some(cleanup));
+                let code = land(cleanup_phase(registers, exception, Some(cleanup), entry));
+                // nothing was landed, so nothing will resume it
+                set_in_flight(exception, None);
+                code
+            }
+            Err(code) => code,
+        };
+    });
+    code
+}
+
+#[cfg_attr(feature = "nightly", unwind(allowed))]
+#[no_mangle]
+pub unsafe extern "C" fn _Unwind_ForcedUnwind(exception: *mut _Unwind_Exception, stop: _Unwind_Stop_Fn,
+                                              stop_argument: *mut c_void) -> _Unwind_Reason_Code {
+    let entry = entry_marker!();
+    (*exception).private_contptr = None;
+    let cleanup = Cleanup::Forced(stop, stop_argument);
+    set_in_flight(exception, Some(cleanup));
+    let mut code = _Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR;
+    ::glue::registers(|registers| {
+        code = land(cleanup_phase(registers, exception, Some(cleanup), entry));
     });
-    unreachable!();
+    // nothing was landed, so nothing will resume it
+    set_in_flight(exception, None);
+    code
+}
+
+// Installs the registers of a landing pad, or returns why there is none.
+unsafe fn land(registers: Result<Registers, _Unwind_Reason_Code>) -> _Unwind_Reason_Code {
+    match registers {
+        // only returns if the registers can't be installed
+        Ok(registers) => {
+            if let Err(e) = ::glue::land(&registers) {
+                error!("cannot land: {}", e);
+            }
+            _Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR
+        }
+        Err(code) => code,
+    }
+}
+
+unsafe fn context(frame: &StackFrame, frames: &mut StackFrames) -> _Unwind_Context {
+    _Unwind_Context {
+        lsda: frame.lsda.unwrap_or(0),
+        ip: frames.registers()[X86_64::RA].unwrap_or(0),
+        initial_address: frame.initial_address,
+        cfa: frames.cfa(),
+        args_size: frame.args_size,
+        interrupted: frames.is_interrupted(),
+        registers: frames.registers(),
+    }
+}
+
+// Phase 1: finds the CFA of the frame that catches the exception.
+unsafe fn search_phase(registers: Registers, exception: *mut _Unwind_Exception, entry: u64)
+                       -> Result<u64, _Unwind_Reason_Code> {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut frames = StackFrames::new(&mut unwinder, registers);
+    skip_own_frames(&mut frames, entry).map_err(|e| {
+        error!("unwinding failed: {}", e);
+        _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR
+    })?;
+    while let Some(frame) = frames.next().map_err(|e| {
+        error!("unwinding failed: {}", e);
+        _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR
+    })? {
+        if let Some(personality) = frame.personality {
+            let personality: PersonalityRoutine = ::std::mem::transmute(personality);
+            let mut ctx = context(&frame, &mut frames);
+            match personality(1, _Unwind_Action::_UA_SEARCH_PHASE as c_int, (*exception).exception_class,
+                              exception, &mut ctx) {
+                _Unwind_Reason_Code::_URC_CONTINUE_UNWIND => (),
+                _Unwind_Reason_Code::_URC_HANDLER_FOUND => return Ok(ctx.cfa),
+                x => {
+                    error!("personality routine returned {:?}", x);
+                    return Err(_Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR);
+                }
+            }
+        }
+    }
+    Err(_Unwind_Reason_Code::_URC_END_OF_STACK)
 }
 
-unsafe fn unwind_tracer(registers: Registers, exception: *mut _Unwind_Exception) -> Option<Registers> {
+// Phase 2: runs the personality routines (and stop function, for forced unwinds)
+// until one has a landing pad to install.
+unsafe fn cleanup_phase(registers: Registers, exception: *mut _Unwind_Exception, cleanup: Option<Cleanup>,
+                        entry: u64) -> Result<Registers, _Unwind_Reason_Code> {
     let mut unwinder = DwarfUnwinder::default();
     let mut frames = StackFrames::new(&mut unwinder, registers);
+    let next_frame = |frames: &mut StackFrames| frames.next().map_err(|e| {
+        error!("unwinding failed: {}", e);
+        _Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR
+    });
+    let class = (*exception).exception_class;
 
     if let Some(contptr) = (*exception).private_contptr {
+        // resumed from a landing pad: continue above its frame
         loop {
-            if let Some(frame) = frames.next().unwrap() {
-                if frames.registers()[X86_64::RSP].unwrap() == contptr {
+            if let Some(frame) = next_frame(&mut frames)? {
+                if frames.registers()[X86_64::RSP] == Some(contptr) {
                     break;
                 }
             } else {
-                return None;
+                return Err(_Unwind_Reason_Code::_URC_END_OF_STACK);
             }
         }
+    } else {
+        skip_own_frames(&mut frames, entry).map_err(|e| {
+            error!("unwinding failed: {}", e);
+            _Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR
+        })?;
     }
 
-    while let Some(frame) = frames.next().unwrap() {
+    while let Some(frame) = next_frame(&mut frames)? {
+        let mut ctx = context(&frame, &mut frames);
+        let mut actions = _Unwind_Action::_UA_CLEANUP_PHASE as c_int;
+        match cleanup {
+            Some(Cleanup::UntilHandler(cfa)) if cfa == ctx.cfa => actions |= _Unwind_Action::_UA_HANDLER_FRAME as c_int,
+            Some(Cleanup::Forced(stop, stop_argument)) => {
+                actions |= _Unwind_Action::_UA_FORCE_UNWIND as c_int;
+                if stop(1, actions, class, exception, &mut ctx, stop_argument) != _Unwind_Reason_Code::_URC_NO_REASON {
+                    return Err(_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR);
+                }
+            }
+            _ => (),
+        }
+
         if let Some(personality) = frame.personality {
             trace!("HAS PERSONALITY");
             let personality: PersonalityRoutine = ::std::mem::transmute(personality);
 
-            let mut ctx = _Unwind_Context {
-                lsda: frame.lsda.unwrap(),
-                ip: frames.registers()[X86_64::RA].unwrap(),
-                initial_address: frame.initial_address,
-                registers: frames.registers(),
-            };
-
-            (*exception).private_contptr = frames.registers()[X86_64::RSP];
-
-            // ABI specifies that phase 1 is optional, so we just run phase 2 (CLEANUP_PHASE)
-            match personality(1, _Unwind_Action::_UA_CLEANUP_PHASE as c_int, (*exception).exception_class,
-                              exception, &mut ctx) {
+            match personality(1, actions, class, exception, &mut ctx) {
                 _Unwind_Reason_Code::_URC_CONTINUE_UNWIND => (),
-                _Unwind_Reason_Code::_URC_INSTALL_CONTEXT => return Some(frames.registers),
-                x => panic!("wtf reason code {:?}", x),
+                _Unwind_Reason_Code::_URC_INSTALL_CONTEXT => {
+                    // The landing pad runs with the stack pointer `_Unwind_SetIP` left,
+                    // which is what _Unwind_Resume will find its frame by.
+                    (*exception).private_contptr = frames.registers()[X86_64::RSP];
+                    if actions & _Unwind_Action::_UA_HANDLER_FRAME as c_int != 0 {
+                        // caught, nothing will resume this exception
+                        set_in_flight(exception, None);
+                    }
+                    return Ok(frames.registers);
+                }
+                x => {
+                    error!("personality routine returned {:?}", x);
+                    return Err(_Unwind_Reason_Code::_URC_FATAL_PHASE2_ERROR);
+                }
             }
         }
     }
-    None
+
+    if let Some(Cleanup::Forced(stop, stop_argument)) = cleanup {
+        let actions = _Unwind_Action::_UA_CLEANUP_PHASE as c_int | _Unwind_Action::_UA_FORCE_UNWIND as c_int
+            | _Unwind_Action::_UA_END_OF_STACK as c_int;
+        let mut ctx = _Unwind_Context {
+            lsda: 0,
+            ip: 0,
+            initial_address: 0,
+            cfa: frames.cfa(),
+            args_size: 0,
+            interrupted: false,
+            registers: frames.registers(),
+        };
+        set_in_flight(exception, None);
+        // usually doesn't return
+        stop(1, actions, class, exception, &mut ctx, stop_argument);
+    }
+    Err(_Unwind_Reason_Code::_URC_END_OF_STACK)
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn _Unwind_Backtrace(trace: _Unwind_Trace_Fn,
                                     trace_argument: *mut c_void)
                                            -> _Unwind_Reason_Code {
-    DwarfUnwinder::default().trace(|frames| {
-        while let Some(frame) = frames.next().unwrap() {
-            let mut ctx = _Unwind_Context {
-                lsda: frame.lsda.unwrap_or(0),
-                ip: frames.registers()[X86_64::RA].unwrap(),
-                initial_address: frame.initial_address,
-                registers: frames.registers(),
+    let entry = entry_marker!();
+    let mut code = _Unwind_Reason_Code::_URC_END_OF_STACK;
+    with_unwinder(&BACKTRACE_UNWINDER, |unwinder| unwinder.trace(|frames| {
+        if let Err(e) = skip_own_frames(frames, entry) {
+            debug!("backtrace stopped: {}", e);
+            code = _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR;
+            return;
+        }
+        loop {
+            let frame = match frames.next() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("backtrace stopped: {}", e);
+                    code = _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR;
+                    break;
+                }
             };
+            let mut ctx = context(&frame, frames);
 
-            trace(&mut ctx, trace_argument);
+            if trace(&mut ctx, trace_argument) != _Unwind_Reason_Code::_URC_NO_REASON {
+                code = _Unwind_Reason_Code::_URC_FATAL_PHASE1_ERROR;
+                break;
+            }
         }
-    });
-    _Unwind_Reason_Code::_URC_END_OF_STACK
+    }));
+    code
 }
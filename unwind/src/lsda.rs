@@ -0,0 +1,263 @@
+//! Decoding of the language-specific data area GCC and LLVM emit for functions with
+//! cleanups or catch clauses (`.gcc_except_table`).
+//!
+//! The layout is the one the Itanium C++ ABI personality routines read: a header, a
+//! call-site table mapping code ranges to landing pads, an action table of chained
+//! type filters, and a type table indexed backwards from its end.
+
+use std::ptr;
+
+use gimli;
+
+use crate::Error;
+
+const DW_EH_PE_OMIT: u8 = 0xff;
+
+/// A parsed LSDA header, pointing into the table in memory.
+#[derive(Debug, Clone)]
+pub struct Lsda {
+    function_start: u64,
+    landing_pad_base: u64,
+    type_encoding: u8,
+    type_table: Option<u64>,
+    call_site_encoding: u8,
+    call_sites: u64,
+    actions: u64,
+}
+
+/// An entry of the call-site table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallSite {
+    /// Runtime address of the first instruction covered.
+    pub start: u64,
+    pub len: u64,
+    /// Where to resume, if anything in this frame runs when unwinding through it.
+    pub landing_pad: Option<u64>,
+    // address of the first action record
+    action: Option<u64>,
+}
+
+/// What the landing pad of a call site does with an exception.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Action {
+    /// Runs destructors and resumes unwinding.
+    Cleanup,
+    /// Catches exceptions matching the type info at this address, or anything if it is 0.
+    Catch { filter: i64, type_info: u64 },
+    /// An exception specification; the filter indexes the list of allowed types.
+    Filter(i64),
+}
+
+impl Lsda {
+    /// Parses the LSDA at `address` of the function starting at `function_start`
+    /// (`StackFrame::lsda` and `StackFrame::initial_address`).
+    ///
+    /// # Safety
+    ///
+    /// The header is read in place at `address`, which must point to an LSDA the
+    /// process has mapped, e.g. one from the CIE augmentation of a loaded object. It
+    /// must stay mapped while the returned `Lsda` is used.
+    pub unsafe fn parse(address: u64, function_start: u64) -> Result<Lsda, Error> {
+        let mut reader = Reader(address);
+
+        let landing_pad_encoding = reader.u8();
+        let landing_pad_base = if landing_pad_encoding == DW_EH_PE_OMIT {
+            function_start
+        } else {
+            reader.pointer(landing_pad_encoding, function_start)?
+        };
+
+        let type_encoding = reader.u8();
+        let type_table = if type_encoding == DW_EH_PE_OMIT {
+            None
+        } else {
+            let offset = reader.uleb128();
+            Some(reader.0.wrapping_add(offset))
+        };
+
+        let call_site_encoding = reader.u8();
+        let call_sites_len = reader.uleb128();
+        let call_sites = reader.0;
+
+        Ok(Lsda {
+            function_start,
+            landing_pad_base,
+            type_encoding,
+            type_table,
+            call_site_encoding,
+            call_sites,
+            actions: call_sites.wrapping_add(call_sites_len),
+        })
+    }
+
+    /// Finds the call site covering `address`, usually the return address minus one.
+    ///
+    /// `None` means the address isn't covered at all, which a C++ runtime treats as a
+    /// call to `std::terminate`.
+    ///
+    /// # Safety
+    ///
+    /// Reads the call-site table in place, so the LSDA passed to `parse` must still be
+    /// mapped.
+    pub unsafe fn call_site(&self, address: u64) -> Result<Option<CallSite>, Error> {
+        let mut reader = Reader(self.call_sites);
+        while reader.0 < self.actions {
+            let start = reader.pointer(self.call_site_encoding, self.function_start)?;
+            let len = reader.pointer(self.call_site_encoding, self.function_start)?;
+            let landing_pad = reader.pointer(self.call_site_encoding, self.function_start)?;
+            let action = reader.uleb128();
+
+            let start = self.function_start.wrapping_add(start);
+            // the table is sorted, so we can stop early
+            if address < start {
+                break;
+            }
+            if address - start < len {
+                return Ok(Some(CallSite {
+                    start,
+                    len,
+                    landing_pad: if landing_pad == 0 { None } else { Some(self.landing_pad_base.wrapping_add(landing_pad)) },
+                    action: if action == 0 { None } else { Some(self.actions.wrapping_add(action - 1)) },
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Lists the actions of a call site in the order they are tried.
+    ///
+    /// # Safety
+    ///
+    /// Reads the action and type tables in place, so the LSDA passed to `parse` must
+    /// still be mapped, and `call_site` must come from this `Lsda`.
+    pub unsafe fn actions(&self, call_site: &CallSite) -> Result<Vec<Action>, Error> {
+        let mut actions = Vec::new();
+        let mut next = match call_site.action {
+            Some(record) => record,
+            None => {
+                if call_site.landing_pad.is_some() {
+                    actions.push(Action::Cleanup);
+                }
+                return Ok(actions);
+            }
+        };
+        // a displacement pointing back into the chain would loop forever
+        let mut records = Vec::new();
+        loop {
+            if records.contains(&next) {
+                return Err(Error::Gimli(gimli::Error::NoEntryAtGivenOffset));
+            }
+            records.push(next);
+            let mut reader = Reader(next);
+            let filter = reader.sleb128();
+            let displacement_at = reader.0;
+            let displacement = reader.sleb128();
+
+            actions.push(match filter {
+                0 => Action::Cleanup,
+                f if f > 0 => Action::Catch { filter: f, type_info: self.type_info(f as u64)? },
+                f => Action::Filter(f),
+            });
+
+            if displacement == 0 {
+                return Ok(actions);
+            }
+            next = displacement_at.wrapping_add(displacement as u64);
+        }
+    }
+
+    unsafe fn type_info(&self, index: u64) -> Result<u64, Error> {
+        let table = self.type_table.ok_or(Error::Gimli(gimli::Error::NoEntryAtGivenOffset))?;
+        let size = match self.type_encoding & 0x0f {
+            0x00 | 0x04 | 0x0c => 8,
+            0x03 | 0x0b => 4,
+            0x02 | 0x0a => 2,
+            _ => return Err(Error::Gimli(gimli::Error::UnsupportedPointerEncoding)),
+        };
+        let offset = index.checked_mul(size).ok_or(Error::Gimli(gimli::Error::NoEntryAtGivenOffset))?;
+        let mut reader = Reader(table.wrapping_sub(offset));
+        reader.pointer(self.type_encoding, self.function_start)
+    }
+}
+
+// A cursor over memory in the current process.
+struct Reader(u64);
+
+impl Reader {
+    unsafe fn u8(&mut self) -> u8 {
+        let x = *(self.0 as *const u8);
+        self.0 = self.0.wrapping_add(1);
+        x
+    }
+
+    unsafe fn read<T: Copy>(&mut self) -> T {
+        let x = ptr::read_unaligned(self.0 as *const T);
+        self.0 = self.0.wrapping_add(::std::mem::size_of::<T>() as u64);
+        x
+    }
+
+    unsafe fn uleb128(&mut self) -> u64 {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8();
+            if shift < 64 {
+                result |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return result;
+            }
+        }
+    }
+
+    unsafe fn sleb128(&mut self) -> i64 {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8();
+            if shift < 64 {
+                result |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    result |= !0 << shift;
+                }
+                return result;
+            }
+        }
+    }
+
+    // Reads a DW_EH_PE_* encoded pointer. Function-relative values are resolved
+    // against `function_start`; text- and data-relative ones don't occur on x86_64.
+    unsafe fn pointer(&mut self, encoding: u8, function_start: u64) -> Result<u64, Error> {
+        if encoding == DW_EH_PE_OMIT {
+            return Err(Error::Gimli(gimli::Error::CannotParseOmitPointerEncoding));
+        }
+        let position = self.0;
+        let value = match encoding & 0x0f {
+            0x00 | 0x04 => self.read::<u64>(),
+            0x01 => self.uleb128(),
+            0x02 => u64::from(self.read::<u16>()),
+            0x03 => u64::from(self.read::<u32>()),
+            0x09 => self.sleb128() as u64,
+            0x0a => self.read::<i16>() as u64,
+            0x0b => self.read::<i32>() as u64,
+            0x0c => self.read::<i64>() as u64,
+            _ => return Err(Error::Gimli(gimli::Error::UnknownPointerEncoding)),
+        };
+        // 0 means "no pointer" in every table that uses these
+        if value == 0 {
+            return Ok(0);
+        }
+        let value = match encoding & 0x70 {
+            0x00 => value,
+            0x10 => position.wrapping_add(value),
+            0x40 => function_start.wrapping_add(value),
+            _ => return Err(Error::Gimli(gimli::Error::UnsupportedPointerEncoding)),
+        };
+        Ok(if encoding & 0x80 != 0 { *(value as *const u64) } else { value })
+    }
+}
@@ -1,4 +1,8 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AddrRange {
     pub start: u64,
     pub end: u64,
@@ -9,7 +13,12 @@ impl AddrRange {
         addr >= self.start && addr < self.end
     }
 
+    /// 0 for an inverted range, as `is_empty` has it.
     pub fn len(&self) -> u64 {
-        self.end - self.start
+        self.end.saturating_sub(self.start)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
     }
 }
@@ -2,9 +2,95 @@ use gimli;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::ops::{Index, IndexMut};
 
-#[derive(Default, Clone, PartialEq, Eq)]
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+// The general purpose registers plus the return address column.
+pub(crate) const REGISTER_COUNT: usize = 17;
+
+// xmm0 to xmm15, which follow the return address column.
+#[cfg(feature = "vector_registers")]
+const VECTOR_COUNT: usize = 16;
+
+/// The registers of a frame, indexed by DWARF register number. Registers that aren't
+/// tracked read as `None`, and writes to them are dropped, since the numbers come from
+/// CFI that may be corrupt.
+#[derive(Default, Clone, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Registers {
-    registers: [Option<u64>; 17],
+    registers: [Option<u64>; REGISTER_COUNT],
+    #[cfg(feature = "vector_registers")]
+    vector: [Option<u128>; VECTOR_COUNT],
+    // where writes to untracked registers go
+    #[cfg_attr(feature = "serde", serde(skip))]
+    untracked: Option<u64>,
+}
+
+impl Registers {
+    /// Whether `reg` is one of the registers recovered while unwinding.
+    pub fn tracks(reg: gimli::Register) -> bool {
+        (reg.0 as usize) < REGISTER_COUNT
+    }
+
+    /// Reads the registers of the interrupted thread from the `ucontext_t` passed to a
+    /// `SA_SIGINFO` signal handler, for use with `StackFrames::from_interrupted`. The
+    /// interrupted instruction goes in the return address column.
+    ///
+    /// # Safety
+    ///
+    /// `ucontext` must point to a valid `ucontext_t`, like the third argument of the
+    /// handler, and stay valid until this returns.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    pub unsafe fn from_ucontext(ucontext: *const libc::c_void) -> Registers {
+        crate::signal::restore(ucontext as u64)
+    }
+
+    /// Like indexing, but by value.
+    pub fn get(&self, reg: gimli::Register) -> Option<u64> {
+        self.registers.get(reg.0 as usize).and_then(|&x| x)
+    }
+
+    /// Whether `reg` is one of the vector registers recovered while unwinding.
+    #[cfg(feature = "vector_registers")]
+    pub fn tracks_vector(reg: gimli::Register) -> bool {
+        Self::vector_index(reg).is_some()
+    }
+
+    /// The value of xmm register `reg` (DWARF numbering), if known. They are all
+    /// caller-saved, so outer frames only have those their CFI saved, e.g. signal frames.
+    #[cfg(feature = "vector_registers")]
+    pub fn vector(&self, reg: gimli::Register) -> Option<u128> {
+        Self::vector_index(reg).and_then(|i| self.vector.get(i).copied().flatten())
+    }
+
+    #[cfg(feature = "vector_registers")]
+    pub fn set_vector(&mut self, reg: gimli::Register, value: Option<u128>) {
+        if let Some(slot) = Self::vector_index(reg).and_then(|i| self.vector.get_mut(i)) {
+            *slot = value;
+        }
+    }
+
+    #[cfg(feature = "vector_registers")]
+    pub(crate) fn clear_vector(&mut self) {
+        self.vector = Default::default();
+    }
+
+    #[cfg(feature = "vector_registers")]
+    fn vector_index(reg: gimli::Register) -> Option<usize> {
+        (reg.0 as usize).checked_sub(REGISTER_COUNT).filter(|&i| i < VECTOR_COUNT)
+    }
+}
+
+impl PartialEq for Registers {
+    fn eq(&self, other: &Registers) -> bool {
+        #[cfg(feature = "vector_registers")]
+        {
+            if self.vector != other.vector {
+                return false;
+            }
+        }
+        self.registers == other.registers
+    }
 }
 
 impl Debug for Registers {
@@ -23,13 +109,19 @@ impl Index<u16> for Registers {
     type Output = Option<u64>;
 
     fn index(&self, index: u16) -> &Option<u64> {
-        &self.registers[index as usize]
+        self.registers.get(index as usize).unwrap_or(&None)
     }
 }
 
 impl IndexMut<u16> for Registers {
     fn index_mut(&mut self, index: u16) -> &mut Option<u64> {
-        &mut self.registers[index as usize]
+        match self.registers.get_mut(index as usize) {
+            Some(slot) => slot,
+            None => {
+                self.untracked = None;
+                &mut self.untracked
+            }
+        }
     }
 }
 
@@ -0,0 +1,68 @@
+//! Unwind info handed over at runtime for code that isn't part of any loaded object,
+//! like the output of a JIT compiler.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use crate::AddrRange;
+
+// The registered .eh_frame blobs.
+static FRAMES: Mutex<Vec<AddrRange>> = Mutex::new(Vec::new());
+
+// Bumped by every deregistration, so unwinders can tell their blobs may be gone
+// without taking the lock.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+// Bumped by every registration, so unwinders kept around can tell they miss blobs.
+static REGISTRATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes the CIEs and FDEs in `eh_frame` (laid out like an .eh_frame section, with
+/// absolute or pc-relative pointers) known to unwinders created from now on.
+///
+/// # Safety
+///
+/// Unwinders read the blob in place, and the code the FDEs describe too (e.g. looking
+/// for signal trampolines). Both must stay mapped until `deregister_frame` has
+/// returned: the `'static` lifetime only holds until then, and freeing either earlier
+/// leaves unwinders reading freed memory. The FDEs' pointers must be valid, as
+/// unwinders follow them.
+pub unsafe fn register_frame(eh_frame: &'static [u8]) {
+    let start = eh_frame.as_ptr() as u64;
+    FRAMES.lock().unwrap_or_else(PoisonError::into_inner).push(AddrRange { start, end: start + eh_frame.len() as u64 });
+    REGISTRATIONS.fetch_add(1, Ordering::Release);
+}
+
+/// Forgets the blob registered at `eh_frame`, returning whether there was one.
+///
+/// Unwinders created before stop using all registered blobs, this one and the others
+/// alike; create a new unwinder to keep unwinding through the rest.
+///
+/// # Safety
+///
+/// The blob and its code may be freed once this returns, so no trace may be running
+/// at that point, on any thread, with an unwinder created while the blob was registered.
+pub unsafe fn deregister_frame(eh_frame: *const u8) -> bool {
+    let mut frames = FRAMES.lock().unwrap_or_else(PoisonError::into_inner);
+    match frames.iter().position(|x| x.start == eh_frame as u64) {
+        Some(i) => {
+            frames.remove(i);
+            GENERATION.fetch_add(1, Ordering::Release);
+            true
+        }
+        None => false,
+    }
+}
+
+// The registered blobs, and the generation they belong to.
+pub(crate) fn registered() -> (usize, Vec<AddrRange>) {
+    let frames = FRAMES.lock().unwrap_or_else(PoisonError::into_inner);
+    (generation(), frames.clone())
+}
+
+pub(crate) fn generation() -> usize {
+    GENERATION.load(Ordering::Acquire)
+}
+
+pub(crate) fn registrations() -> usize {
+    REGISTRATIONS.load(Ordering::Acquire)
+}
@@ -0,0 +1,161 @@
+//! CFI evaluated into fixed storage, for `DwarfUnwinder::trace_into`. gimli's unwind
+//! tables keep their rows in vectors, so they can't be used without the allocator.
+
+use gimli::{BaseAddresses, CallFrameInstruction, CfaRule, FrameDescriptionEntry, RegisterRule, UnwindSection};
+
+use crate::registers::REGISTER_COUNT;
+use crate::{Error, StaticReader};
+
+// How many DW_CFA_remember_state may be outstanding. Compilers don't nest them.
+const STATE_DEPTH: usize = 2;
+
+type Rules = [Option<RegisterRule<StaticReader>>; REGISTER_COUNT];
+
+#[derive(Clone, Default)]
+struct State {
+    cfa: Option<CfaRule<StaticReader>>,
+    rules: Rules,
+}
+
+/// The row of an FDE's unwind table covering one address, with the rules of the
+/// registers `Registers` tracks. Rules for other registers are dropped.
+#[derive(Default)]
+pub struct FixedRow {
+    state: State,
+}
+
+impl FixedRow {
+    /// Runs the instructions of `fde` up to `address`, replacing what the row held.
+    pub fn evaluate<S: UnwindSection<StaticReader>>(
+        &mut self,
+        section: &S,
+        bases: &BaseAddresses,
+        fde: &FrameDescriptionEntry<StaticReader>,
+        address: u64,
+    ) -> Result<(), Error> {
+        let cie = fde.cie();
+        let mut evaluator = Evaluator {
+            state: &mut self.state,
+            initial: Default::default(),
+            stack: Default::default(),
+            depth: 0,
+            data_alignment: cie.data_alignment_factor(),
+        };
+        *evaluator.state = State::default();
+
+        let mut instructions = cie.instructions(section, bases);
+        while let Some(instruction) = instructions.next()? {
+            evaluator.execute(instruction)?;
+        }
+        evaluator.initial = evaluator.state.rules.clone();
+
+        let code_alignment = cie.code_alignment_factor();
+        let mut location = fde.initial_address();
+        let mut instructions = fde.instructions(section, bases);
+        while let Some(instruction) = instructions.next()? {
+            match instruction {
+                CallFrameInstruction::AdvanceLoc { delta } => {
+                    location = location.wrapping_add(u64::from(delta).wrapping_mul(code_alignment));
+                }
+                CallFrameInstruction::SetLoc { address: next } => location = next,
+                instruction => {
+                    evaluator.execute(instruction)?;
+                    continue;
+                }
+            }
+            // the rules from here on are for later rows
+            if location > address {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cfa(&self) -> Result<&CfaRule<StaticReader>, Error> {
+        self.state.cfa.as_ref().ok_or(Error::Gimli(gimli::Error::CfiInstructionInInvalidContext))
+    }
+
+    /// The registers with a rule, like `UnwindTableRow::registers`.
+    pub fn registers(&self) -> impl Iterator<Item = (gimli::Register, &RegisterRule<StaticReader>)> {
+        self.state.rules.iter().enumerate()
+            .filter_map(|(reg, rule)| rule.as_ref().map(|rule| (gimli::Register(reg as u16), rule)))
+    }
+}
+
+struct Evaluator<'a> {
+    state: &'a mut State,
+    // the rules after the CIE's instructions, for DW_CFA_restore
+    initial: Rules,
+    stack: [State; STATE_DEPTH],
+    depth: usize,
+    data_alignment: i64,
+}
+
+impl<'a> Evaluator<'a> {
+    fn execute(&mut self, instruction: CallFrameInstruction<StaticReader>) -> Result<(), Error> {
+        use gimli::CallFrameInstruction::*;
+
+        let data_alignment = self.data_alignment;
+        match instruction {
+            DefCfa { register, offset } => self.state.cfa = Some(CfaRule::RegisterAndOffset { register, offset: offset as i64 }),
+            DefCfaSf { register, factored_offset } => self.state.cfa = Some(CfaRule::RegisterAndOffset {
+                register,
+                offset: factored_offset.wrapping_mul(data_alignment),
+            }),
+            DefCfaRegister { register: new } => match self.state.cfa {
+                Some(CfaRule::RegisterAndOffset { ref mut register, .. }) => *register = new,
+                _ => return Err(Error::Gimli(gimli::Error::CfiInstructionInInvalidContext)),
+            },
+            DefCfaOffset { offset: new } => self.set_cfa_offset(new as i64)?,
+            DefCfaOffsetSf { factored_offset } => self.set_cfa_offset(factored_offset.wrapping_mul(data_alignment))?,
+            DefCfaExpression { expression } => self.state.cfa = Some(CfaRule::Expression(expression)),
+            Undefined { register } => self.set_rule(register, Some(RegisterRule::Undefined)),
+            SameValue { register } => self.set_rule(register, Some(RegisterRule::SameValue)),
+            Offset { register, factored_offset } =>
+                self.set_rule(register, Some(RegisterRule::Offset((factored_offset as i64).wrapping_mul(data_alignment)))),
+            OffsetExtendedSf { register, factored_offset } =>
+                self.set_rule(register, Some(RegisterRule::Offset(factored_offset.wrapping_mul(data_alignment)))),
+            ValOffset { register, factored_offset } =>
+                self.set_rule(register, Some(RegisterRule::ValOffset((factored_offset as i64).wrapping_mul(data_alignment)))),
+            ValOffsetSf { register, factored_offset } =>
+                self.set_rule(register, Some(RegisterRule::ValOffset(factored_offset.wrapping_mul(data_alignment)))),
+            Register { dest_register, src_register } => self.set_rule(dest_register, Some(RegisterRule::Register(src_register))),
+            Expression { register, expression } => self.set_rule(register, Some(RegisterRule::Expression(expression))),
+            ValExpression { register, expression } => self.set_rule(register, Some(RegisterRule::ValExpression(expression))),
+            Restore { register } => {
+                let rule = self.initial.get(register.0 as usize).cloned().flatten();
+                self.set_rule(register, rule);
+            }
+            RememberState => {
+                let slot = self.stack.get_mut(self.depth).ok_or(Error::Gimli(gimli::Error::CfiInstructionInInvalidContext))?;
+                slot.clone_from(self.state);
+                self.depth += 1;
+            }
+            RestoreState => {
+                self.depth = self.depth.checked_sub(1).ok_or(Error::Gimli(gimli::Error::PopWithEmptyStack))?;
+                if let Some(saved) = self.stack.get(self.depth) {
+                    self.state.clone_from(saved);
+                }
+            }
+            // locations are handled by the caller, and the argument size isn't needed
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn set_cfa_offset(&mut self, new: i64) -> Result<(), Error> {
+        match self.state.cfa {
+            Some(CfaRule::RegisterAndOffset { ref mut offset, .. }) => {
+                *offset = new;
+                Ok(())
+            }
+            _ => Err(Error::Gimli(gimli::Error::CfiInstructionInInvalidContext)),
+        }
+    }
+
+    fn set_rule(&mut self, register: gimli::Register, rule: Option<RegisterRule<StaticReader>>) {
+        if let Some(slot) = self.state.rules.get_mut(register.0 as usize) {
+            *slot = rule;
+        }
+    }
+}
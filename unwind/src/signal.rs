@@ -0,0 +1,58 @@
+//! Unwinding through the kernel's signal frames by recognizing the return trampoline,
+//! since musl and some vDSOs don't describe it with CFI.
+
+#[cfg(feature = "vector_registers")]
+use std::ptr;
+
+use gimli::X86_64;
+
+use crate::registers::Registers;
+
+// mov $15 (rt_sigreturn), %rax; syscall
+const RESTORE_RT: [u8; 9] = [0x48, 0xc7, 0xc0, 0x0f, 0x00, 0x00, 0x00, 0x0f, 0x05];
+
+// Offset of uc_mcontext.gregs in the kernel's ucontext_t.
+const GREGS_OFFSET: u64 = 40;
+
+// Offset of uc_mcontext.fpregs, right after the 23 gregs.
+#[cfg(feature = "vector_registers")]
+const FPREGS_OFFSET: u64 = GREGS_OFFSET + 23 * 8;
+
+// Offset of xmm0 in the fxsave area.
+#[cfg(feature = "vector_registers")]
+const XMM_OFFSET: u64 = 160;
+
+// The registers in gregs, in order (REG_R8 to REG_RIP).
+const GREGS: [gimli::Register; 17] = [
+    X86_64::R8, X86_64::R9, X86_64::R10, X86_64::R11, X86_64::R12, X86_64::R13, X86_64::R14, X86_64::R15,
+    X86_64::RDI, X86_64::RSI, X86_64::RBP, X86_64::RBX, X86_64::RDX, X86_64::RAX, X86_64::RCX, X86_64::RSP,
+    X86_64::RA,
+];
+
+/// Whether the code at `address` is an `rt_sigreturn` trampoline like glibc's and musl's
+/// `__restore_rt`. The caller must make sure the 9 bytes there are mapped.
+pub unsafe fn is_sigreturn(address: u64) -> bool {
+    *(address as *const [u8; 9]) == RESTORE_RT
+}
+
+/// Reads the registers the kernel saved in the `ucontext_t` at `ucontext`. The
+/// interrupted instruction pointer goes in the return address column.
+pub unsafe fn restore(ucontext: u64) -> Registers {
+    let gregs = (ucontext + GREGS_OFFSET) as *const u64;
+    let mut registers = Registers::default();
+    for (i, &reg) in GREGS.iter().enumerate() {
+        registers[reg] = Some(*gregs.add(i));
+    }
+    #[cfg(feature = "vector_registers")]
+    {
+        // mcontext_t.fpregs, pointing at the fxsave area
+        let fpregs = *((ucontext + FPREGS_OFFSET) as *const u64);
+        if fpregs != 0 {
+            let xmm = (fpregs + XMM_OFFSET) as *const u128;
+            for i in 0..16 {
+                registers.set_vector(gimli::Register(17 + i), Some(ptr::read_unaligned(xmm.add(i as usize))));
+            }
+        }
+    }
+    registers
+}
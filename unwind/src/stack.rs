@@ -0,0 +1,50 @@
+//! Where the stacks are, so the strategies that guess only read memory a stack holds.
+
+#[cfg(unix)]
+use std::{mem, ptr};
+
+use crate::AddrRange;
+
+/// The calling thread's stack, if the platform tells.
+#[cfg(target_os = "linux")]
+pub fn thread_stack() -> Option<AddrRange> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut addr = ptr::null_mut();
+        let mut size = 0;
+        let found = libc::pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+        libc::pthread_attr_destroy(&mut attr);
+        if !found {
+            return None;
+        }
+        let start = addr as u64;
+        Some(AddrRange { start, end: start.checked_add(size as u64)? })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn thread_stack() -> Option<AddrRange> {
+    None
+}
+
+/// The calling thread's alternate signal stack, if it has one. Safe to call from a
+/// signal handler.
+#[cfg(unix)]
+pub fn alt_stack() -> Option<AddrRange> {
+    unsafe {
+        let mut current: libc::stack_t = mem::zeroed();
+        if libc::sigaltstack(ptr::null(), &mut current) != 0 || current.ss_flags & libc::SS_DISABLE != 0 {
+            return None;
+        }
+        let start = current.ss_sp as u64;
+        Some(AddrRange { start, end: start.checked_add(current.ss_size as u64)? })
+    }
+}
+
+#[cfg(not(unix))]
+pub fn alt_stack() -> Option<AddrRange> {
+    None
+}
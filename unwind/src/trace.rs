@@ -0,0 +1,245 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fallible_iterator::FallibleIterator;
+
+#[cfg(feature = "serde")]
+use serde::{Serialize, Deserialize};
+
+use crate::{AddrRange, DwarfUnwinder, Registers, StackFrame, StopReason, Unwinder, X86_64};
+
+/// A frame of an owned trace, with the registers recovered for it.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct TraceFrame {
+    pub frame: StackFrame,
+    pub registers: Registers,
+    /// The index of the module in `ProcessMetadata::modules` and the return address
+    /// relative to it, which unlike the address itself doesn't change with ASLR.
+    /// Filled in by `ProcessTrace::normalize`.
+    pub module_relative: Option<(usize, u64)>,
+}
+
+/// The unwound stack of one thread.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct ThreadTrace {
+    pub tid: Option<u64>,
+    pub name: Option<String>,
+    pub frames: Vec<TraceFrame>,
+    /// `None` if unwinding stopped on an error.
+    pub stop_reason: Option<StopReason>,
+}
+
+/// The unwound stacks of a whole process, e.g. assembled from a core dump.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct ProcessTrace {
+    pub threads: Vec<ThreadTrace>,
+    /// What is needed to symbolize the trace elsewhere, if it was recorded.
+    pub metadata: Option<ProcessMetadata>,
+}
+
+/// A loaded object, to match addresses back to files and symbols offline.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct TraceModule {
+    pub path: Option<PathBuf>,
+    /// The executable segment at runtime.
+    pub text: AddrRange,
+    /// Difference between runtime and link-time addresses.
+    pub bias: u64,
+    pub build_id: Option<Vec<u8>>,
+}
+
+/// Where and when a trace was captured.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct ProcessMetadata {
+    pub executable: Option<PathBuf>,
+    pub modules: Vec<TraceModule>,
+    pub os: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+}
+
+impl TraceFrame {
+    pub fn new(frame: StackFrame, registers: Registers) -> TraceFrame {
+        TraceFrame { frame, registers, module_relative: None }
+    }
+}
+
+impl ProcessTrace {
+    pub fn new(threads: Vec<ThreadTrace>, metadata: Option<ProcessMetadata>) -> ProcessTrace {
+        ProcessTrace { threads, metadata }
+    }
+
+    /// Fills in `TraceFrame::module_relative` from the metadata, so that traces of
+    /// different runs can be compared, e.g. to fingerprint crashes.
+    pub fn normalize(&mut self) {
+        if let Some(ref metadata) = self.metadata {
+            for frame in self.threads.iter_mut().flat_map(|thread| thread.frames.iter_mut()) {
+                frame.module_relative = frame.registers[X86_64::RA].and_then(|ra| metadata.module_index(ra));
+            }
+        }
+    }
+}
+
+impl TraceModule {
+    /// A module known only by where it was loaded, e.g. one read from a core dump.
+    pub fn new(text: AddrRange, bias: u64) -> TraceModule {
+        TraceModule { path: None, text, bias, build_id: None }
+    }
+}
+
+impl ProcessMetadata {
+    /// Metadata with neither executable nor timestamp, to be filled in by the caller.
+    pub fn new(os: String, modules: Vec<TraceModule>) -> ProcessMetadata {
+        ProcessMetadata { executable: None, modules, os, timestamp: None }
+    }
+
+    /// Describes the current process and the objects `unwinder` knows about.
+    pub fn current(unwinder: &DwarfUnwinder) -> ProcessMetadata {
+        let executable = std::env::current_exe().ok();
+        let modules = unwinder.cfi.iter().map(|rec| TraceModule {
+            // /proc/self/exe means nothing once the trace leaves this process
+            path: match rec.er.path {
+                Some(ref path) if path == Path::new("/proc/self/exe") => executable.clone(),
+                ref path => path.clone(),
+            },
+            text: rec.er.text,
+            bias: rec.er.bias,
+            build_id: rec.er.build_id.clone(),
+        }).collect();
+
+        ProcessMetadata {
+            executable,
+            modules,
+            os: std::env::consts::OS.to_string(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|x| x.as_secs()),
+        }
+    }
+
+    /// The module containing `address`, and the address relative to the module's link-time
+    /// base. Together with the build ID, that is enough to symbolize the frame later
+    /// against a better debug file.
+    pub fn module_relative(&self, address: u64) -> Option<(&TraceModule, u64)> {
+        self.modules.iter()
+            .find(|module| module.text.contains(address))
+            .map(|module| (module, address.wrapping_sub(module.bias)))
+    }
+
+    fn module_index(&self, address: u64) -> Option<(usize, u64)> {
+        self.modules.iter()
+            .enumerate()
+            .find(|(_, module)| module.text.contains(address))
+            .map(|(i, module)| (i, address.wrapping_sub(module.bias)))
+    }
+
+    /// Removes the paths `redact` picks, e.g. of proprietary modules, before the trace
+    /// is shared. Addresses and build IDs stay, so the frames can still be symbolized by
+    /// whoever has the matching debug files.
+    pub fn scrub<F: Fn(&Path) -> bool>(&mut self, redact: F) {
+        let scrub_path = |path: &mut Option<PathBuf>| {
+            if path.as_ref().map_or(false, |path| redact(path)) {
+                *path = None;
+            }
+        };
+        scrub_path(&mut self.executable);
+        for module in &mut self.modules {
+            scrub_path(&mut module.path);
+        }
+    }
+}
+
+/// Return addresses of the calling thread, captured with `DwarfUnwinder::trace_into`
+/// without touching the allocator. Frames beyond `N` are dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace<const N: usize> {
+    pcs: [u64; N],
+    len: usize,
+}
+
+impl<const N: usize> Backtrace<N> {
+    pub fn capture(unwinder: &mut DwarfUnwinder) -> Backtrace<N> {
+        let mut pcs = [0; N];
+        let len = unwinder.trace_into(&mut pcs);
+        Backtrace { pcs, len }
+    }
+
+    /// The return addresses, innermost first.
+    pub fn pcs(&self) -> &[u64] {
+        self.pcs.get(..self.len).unwrap_or(&[])
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<u64> {
+        self.pcs().iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<'a, const N: usize> IntoIterator for &'a Backtrace<N> {
+    type Item = &'a u64;
+    type IntoIter = std::slice::Iter<'a, u64>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl ThreadTrace {
+    /// A trace of an unnamed thread, e.g. one unwound from a core dump.
+    pub fn new(frames: Vec<TraceFrame>, stop_reason: Option<StopReason>) -> ThreadTrace {
+        ThreadTrace { tid: None, name: None, frames, stop_reason }
+    }
+
+    /// Unwinds the calling thread. The first frames belong to the unwinder itself.
+    pub fn capture(unwinder: &mut DwarfUnwinder) -> ThreadTrace {
+        let mut frames = Vec::new();
+        let mut stop_reason = None;
+        unwinder.trace(|iter| {
+            loop {
+                match iter.next() {
+                    Ok(Some(frame)) => frames.push(TraceFrame::new(frame, iter.registers().clone())),
+                    Ok(None) => {
+                        stop_reason = iter.stop_reason();
+                        break;
+                    }
+                    Err(e) => {
+                        debug!("trace stopped: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        ThreadTrace {
+            tid: current_tid(),
+            name: std::thread::current().name().map(String::from),
+            frames,
+            stop_reason,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn current_tid() -> Option<u64> {
+    Some(unsafe { libc::syscall(libc::SYS_gettid) } as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_tid() -> Option<u64> {
+    None
+}
@@ -2,7 +2,10 @@ extern crate unwind;
 extern crate backtrace;
 extern crate fallible_iterator;
 
-use unwind::{Unwinder, DwarfUnwinder};
+use std::sync::Arc;
+
+use unwind::{Unwinder, DwarfUnwinder, StackFrames, Registers, X86_64, StopReason, FrameTrust, Strategy, UnwindCache, RegisterRecovery, AddrRange, ProcessMetadata, ProcessTrace, ThreadTrace, TraceFrame, TraceModule, Backtrace, Error, Control, UnwindHint};
+use unwind::lsda::{Lsda, Action};
 use fallible_iterator::FallibleIterator;
 
 #[test]
@@ -10,6 +13,524 @@ fn correctness() {
     test_frame_1();
 }
 
+// A frame whose CFA and saved rbp are only described by DWARF expressions,
+// the way glibc's hand-written assembly and the PLT describe theirs.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl expression_frame
+    .type expression_frame,@function
+expression_frame:
+    .cfi_startproc
+    pushq %rbp
+    .cfi_def_cfa_offset 16
+    .cfi_offset rbp, -16
+    movq %rsp, %rbp
+    # DW_CFA_def_cfa_expression: DW_OP_breg6 (rbp) 16
+    .cfi_escape 0x0f, 0x02, 0x76, 0x10
+    # DW_CFA_expression rbp: DW_OP_breg6 (rbp) 0
+    .cfi_escape 0x10, 0x06, 0x02, 0x76, 0x00
+    call *%rdi
+    popq %rbp
+    .cfi_def_cfa rsp, 8
+    ret
+    .cfi_endproc
+"#);
+
+// The same, computed with DWARF arithmetic and DW_OP_call_frame_cfa.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl expression_arithmetic_frame
+    .type expression_arithmetic_frame,@function
+expression_arithmetic_frame:
+    .cfi_startproc
+    pushq %rbp
+    .cfi_def_cfa_offset 16
+    .cfi_offset rbp, -16
+    movq %rsp, %rbp
+    # DW_CFA_def_cfa_expression: DW_OP_breg6 (rbp) 0; DW_OP_lit16; DW_OP_plus
+    .cfi_escape 0x0f, 0x04, 0x76, 0x00, 0x40, 0x22
+    # DW_CFA_expression rbp: DW_OP_call_frame_cfa; DW_OP_lit16; DW_OP_minus
+    .cfi_escape 0x10, 0x06, 0x03, 0x9c, 0x40, 0x1c
+    call *%rdi
+    popq %rbp
+    .cfi_def_cfa rsp, 8
+    ret
+    .cfi_endproc
+"#);
+
+// Callee-saved registers described by register and same-value rules.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl register_rules_frame
+    .type register_rules_frame,@function
+register_rules_frame:
+    .cfi_startproc
+    pushq %rbx
+    .cfi_def_cfa_offset 16
+    .cfi_offset rbx, -16
+    movq %rbp, %rbx
+    .cfi_register rbp, rbx
+    .cfi_same_value r12
+    call *%rdi
+    movq %rbx, %rbp
+    .cfi_restore rbp
+    popq %rbx
+    .cfi_def_cfa_offset 8
+    ret
+    .cfi_endproc
+"#);
+
+// A frame with a frame pointer but no CFI at all.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl frame_pointer_frame
+    .type frame_pointer_frame,@function
+frame_pointer_frame:
+    pushq %rbp
+    movq %rsp, %rbp
+    call *%rdi
+    popq %rbp
+    ret
+"#);
+
+// Neither CFI nor a frame pointer; the locals are zeroed so the only code address
+// on its stack is the return address.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl frameless_frame
+    .type frameless_frame,@function
+frameless_frame:
+    subq $24, %rsp
+    movq $0, (%rsp)
+    movq $0, 8(%rsp)
+    movq $0, 16(%rsp)
+    call *%rdi
+    addq $24, %rsp
+    ret
+"#);
+
+// Calls with 16 bytes of arguments pushed, as GCC does without
+// -maccumulate-outgoing-args.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl args_size_frame
+    .type args_size_frame,@function
+args_size_frame:
+    .cfi_startproc
+    subq $8, %rsp
+    .cfi_def_cfa_offset 16
+    pushq $0
+    .cfi_def_cfa_offset 24
+    pushq $0
+    .cfi_def_cfa_offset 32
+    # DW_CFA_GNU_args_size 16
+    .cfi_escape 0x2e, 0x10
+    call *%rdi
+    addq $16, %rsp
+    .cfi_escape 0x2e, 0x00
+    .cfi_def_cfa_offset 16
+    addq $8, %rsp
+    .cfi_def_cfa_offset 8
+    ret
+    .cfi_endproc
+"#);
+
+// Spills xmm6 across the call, loaded from `value` first so there is something to find.
+#[cfg(all(target_arch = "x86_64", feature = "vector_registers"))]
+std::arch::global_asm!(r#"
+    .globl vector_frame
+    .type vector_frame,@function
+vector_frame:
+    .cfi_startproc
+    subq $24, %rsp
+    .cfi_def_cfa_offset 32
+    movdqu (%rsi), %xmm6
+    movdqu %xmm6, (%rsp)
+    # xmm6 is DWARF register 23
+    .cfi_offset 23, -32
+    call *%rdi
+    addq $24, %rsp
+    .cfi_def_cfa_offset 8
+    ret
+    .cfi_endproc
+"#);
+
+#[cfg(all(target_arch = "x86_64", feature = "vector_registers"))]
+extern "C" {
+    fn vector_frame(f: extern "C" fn(), value: *const u128);
+}
+
+// No CFI at all, only what `registered_frame` hands over at runtime.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl nocfi_frame
+    .type nocfi_frame,@function
+nocfi_frame:
+    subq $8, %rsp
+    call *%rdi
+    addq $8, %rsp
+    ret
+    .globl nocfi_frame_end
+nocfi_frame_end:
+"#);
+
+#[cfg(target_arch = "x86_64")]
+extern "C" {
+    fn nocfi_frame(f: extern "C" fn());
+    fn nocfi_frame_end();
+}
+
+// A stub without CFI, unwound with an `UnwindHint` in `unwind_hint`.
+#[cfg(target_arch = "x86_64")]
+std::arch::global_asm!(r#"
+    .globl hint_frame
+    .type hint_frame,@function
+hint_frame:
+    subq $24, %rsp
+    movq %rbx, (%rsp)
+    movq $0, %rbx
+    call *%rdi
+    movq (%rsp), %rbx
+    addq $24, %rsp
+    ret
+    .globl hint_frame_end
+hint_frame_end:
+"#);
+
+#[cfg(target_arch = "x86_64")]
+extern "C" {
+    fn hint_frame(f: extern "C" fn());
+    fn hint_frame_end();
+}
+
+#[cfg(target_arch = "x86_64")]
+extern "C" {
+    fn expression_frame(f: extern "C" fn());
+    fn expression_arithmetic_frame(f: extern "C" fn());
+    fn register_rules_frame(f: extern "C" fn());
+    fn frame_pointer_frame(f: extern "C" fn());
+    fn frameless_frame(f: extern "C" fn());
+    fn args_size_frame(f: extern "C" fn());
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn cfi_expressions() {
+    extern "C" fn callback() { test_frame_3() }
+    unsafe { expression_frame(callback) };
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn cfi_expression_arithmetic() {
+    extern "C" fn callback() { test_frame_3() }
+    unsafe { expression_arithmetic_frame(callback) };
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn cfi_register_rules() {
+    extern "C" fn callback() { test_frame_3() }
+    unsafe { register_rules_frame(callback) };
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn frame_pointer_fallback() {
+    extern "C" fn callback() {
+        let mut unwinder = DwarfUnwinder::default();
+        unwinder.set_strategies(&[Strategy::Cfi, Strategy::FramePointer]);
+        unwinder.trace(|frames| {
+            let mut trust = Vec::new();
+            while let Some(frame) = frames.next().unwrap() {
+                trust.push(frame.trust());
+            }
+            assert_eq!(trust.iter().filter(|&&x| x == FrameTrust::FramePointer).count(), 1);
+        });
+    }
+    unsafe { frame_pointer_frame(callback) };
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn stack_scan_fallback() {
+    extern "C" fn callback() {
+        let mut unwinder = DwarfUnwinder::default();
+        unwinder.set_strategies(&[Strategy::Cfi, Strategy::StackScan]);
+        unwinder.trace(|frames| {
+            let mut trust = Vec::new();
+            while let Some(frame) = frames.next().unwrap() {
+                trust.push(frame.trust());
+            }
+            assert_eq!(trust.iter().filter(|&&x| x == FrameTrust::Scanned).count(), 1);
+        });
+    }
+    unsafe { frameless_frame(callback) };
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn gnu_args_size() {
+    extern "C" fn callback() {
+        let mut found = false;
+        DwarfUnwinder::default().trace(|frames| {
+            while let Some(frame) = frames.next().unwrap() {
+                if frame.initial_address() == args_size_frame as usize as u64 {
+                    assert_eq!(frame.args_size(), 16);
+                    found = true;
+                }
+            }
+        });
+        assert!(found);
+    }
+    unsafe { args_size_frame(callback) };
+}
+
+#[cfg(feature = "vector_registers")]
+static VECTOR: u128 = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210;
+
+#[test]
+#[cfg(feature = "vector_registers")]
+fn vector_registers() {
+    extern "C" fn callback() {
+        let mut found = false;
+        DwarfUnwinder::default().trace(|frames| {
+            let mut spilled = false;
+            while let Some(frame) = frames.next().unwrap() {
+                let xmm6 = frames.registers().vector(X86_64::XMM6);
+                if spilled {
+                    assert_eq!(xmm6, Some(VECTOR));
+                    found = true;
+                } else {
+                    // caller-saved, so never recovered without a rule
+                    assert_eq!(xmm6, None);
+                }
+                spilled = frame.initial_address() == vector_frame as usize as u64;
+            }
+        });
+        assert!(found);
+    }
+    unsafe { vector_frame(callback, &VECTOR) };
+}
+
+// An .eh_frame blob like a JIT would emit for `nocfi_frame`.
+#[cfg(target_arch = "x86_64")]
+fn nocfi_eh_frame() -> Vec<u8> {
+    let start = nocfi_frame as usize as u64;
+    let len = nocfi_frame_end as usize as u64 - start;
+    let mut data = Vec::new();
+    // CIE: "zR" with absolute pointers, alignment 1/-8, return address in r16,
+    // CFA = rsp + 8 and the return address at CFA - 8, padded with DW_CFA_nop
+    data.extend_from_slice(&20u32.to_ne_bytes());
+    data.extend_from_slice(&[0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 16, 1, 0x00]);
+    data.extend_from_slice(&[0x0c, 0x07, 0x08, 0x90, 0x01, 0, 0]);
+    // FDE: after the 4 bytes of subq, CFA = rsp + 16
+    data.extend_from_slice(&28u32.to_ne_bytes());
+    data.extend_from_slice(&28u32.to_ne_bytes());
+    data.extend_from_slice(&start.to_ne_bytes());
+    data.extend_from_slice(&len.to_ne_bytes());
+    data.extend_from_slice(&[0, 0x44, 0x0e, 0x10, 0, 0, 0, 0]);
+    data.extend_from_slice(&0u32.to_ne_bytes());
+    data
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn registered_frame() {
+    extern "C" fn callback() {
+        let mut found = false;
+        DwarfUnwinder::default().trace(|frames| {
+            while let Some(frame) = frames.next().unwrap() {
+                if frame.initial_address() == nocfi_frame as usize as u64 {
+                    found = true;
+                }
+            }
+        });
+        assert!(found);
+    }
+    let eh_frame: &'static [u8] = Box::leak(nocfi_eh_frame().into_boxed_slice());
+    unsafe { unwind::register_frame(eh_frame) };
+    unsafe { nocfi_frame(callback) };
+    let unwinder = DwarfUnwinder::default();
+    assert!(unwinder.is_code_address(nocfi_frame as usize as u64));
+    assert!(unsafe { unwind::deregister_frame(eh_frame.as_ptr()) });
+    assert!(!unsafe { unwind::deregister_frame(eh_frame.as_ptr()) });
+    // the blob may be gone, so even unwinders created before leave it alone
+    assert!(!unwinder.is_code_address(nocfi_frame as usize as u64));
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn unwind_hint() {
+    extern "C" fn callback() {
+        let mut unwinder = DwarfUnwinder::default();
+        let range = AddrRange { start: hint_frame as usize as u64, end: hint_frame_end as usize as u64 };
+        unwinder.add_unwind_hint(range, UnwindHint::new(X86_64::RSP, 32, vec![(X86_64::RBX, -32)]));
+        let mut found = false;
+        unwinder.trace(|frames| {
+            while let Some(frame) = frames.next().unwrap() {
+                if frame.initial_address() == range.start {
+                    assert_eq!(frame.trust(), FrameTrust::Cfi);
+                    let rules: Vec<_> = frames.register_rules().collect();
+                    assert!(rules.contains(&(X86_64::RBX, RegisterRecovery::Offset(-32))));
+                    assert!(rules.contains(&(X86_64::RA, RegisterRecovery::Offset(-8))));
+                    found = true;
+                }
+            }
+        });
+        assert!(found);
+    }
+    unsafe { hint_frame(callback) };
+}
+
+#[test]
+fn max_frames() {
+    let mut unwinder = DwarfUnwinder::default();
+    unwinder.set_max_frames(Some(2));
+    unwinder.trace(|frames| {
+        assert!(frames.next().unwrap().is_some());
+        assert!(frames.next().unwrap().is_some());
+        assert!(frames.next().unwrap().is_none());
+        assert_eq!(frames.stop_reason(), Some(StopReason::MaxDepth));
+    });
+}
+
+#[test]
+fn skip_frames() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut traces = Vec::new();
+    for &skip in &[0, 2] {
+        unwinder.set_skip_frames(skip);
+        let mut trace = Vec::new();
+        unwinder.trace(|frames| {
+            while let Some(frame) = frames.next().unwrap() {
+                trace.push(frame.initial_address());
+            }
+        });
+        traces.push(trace);
+    }
+    assert_eq!(&traces[0][2..], &traces[1][..]);
+}
+
+#[test]
+fn stop_condition() {
+    let sentinel = stop_condition as usize as u64;
+    let mut unwinder = DwarfUnwinder::default();
+    unwinder.set_stop_condition(move |frame, _| {
+        if frame.initial_address() == sentinel { Control::Stop } else { Control::Continue }
+    });
+    unwinder.trace(|frames| {
+        while let Some(frame) = frames.next().unwrap() {
+            assert!(frame.initial_address() != sentinel);
+        }
+        assert_eq!(frames.stop_reason(), Some(StopReason::Requested));
+    });
+}
+
+#[test]
+fn register_rules() {
+    DwarfUnwinder::default().trace(|frames| {
+        let frame = frames.next().unwrap().unwrap();
+        assert_eq!(frame.cfa(), frames.cfa());
+        assert!(frames.register_rules().any(|rule| rule == (X86_64::RA, RegisterRecovery::Offset(-8))));
+    });
+}
+
+#[test]
+fn callee_saved_registers() {
+    DwarfUnwinder::default().trace(|frames| {
+        frames.registers()[X86_64::RAX] = Some(1);
+        assert!(frames.registers()[X86_64::RBX].is_some());
+        frames.next().unwrap().unwrap();
+        frames.next().unwrap().unwrap();
+        assert_eq!(frames.registers()[X86_64::RAX], None);
+        assert!(frames.registers()[X86_64::RBX].is_some());
+    });
+}
+
+#[test]
+fn repeated_frame() {
+    DwarfUnwinder::default().trace(|frames| {
+        let start = frames.registers().clone();
+        let mut unwinder = DwarfUnwinder::default();
+        let hook_start = start.clone();
+        // a broken context switch hook that always goes back to the same place
+        unwinder.add_context_switch(AddrRange { start: 0, end: !0 }, move |_| Some(hook_start.clone()));
+        let mut frames = StackFrames::new(&mut unwinder, start);
+        assert!(frames.next().unwrap().is_some());
+        assert!(frames.next().unwrap().is_none());
+        assert_eq!(frames.stop_reason(), Some(StopReason::Cycle));
+    });
+}
+
+#[test]
+fn fixed_capacity_backtrace() {
+    let mut unwinder = DwarfUnwinder::default();
+    let full = Backtrace::<256>::capture(&mut unwinder);
+    let short = Backtrace::<2>::capture(&mut unwinder);
+    assert!(full.iter().all(|&pc| unwinder.is_code_address(pc)));
+    assert!(full.len() > 2);
+    assert_eq!(short.len(), 2);
+    assert_eq!(short.iter().count(), 2);
+}
+
+#[test]
+fn bogus_registers() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut registers = Registers::default();
+    registers[X86_64::RA] = Some(1);
+    match StackFrames::new(&mut unwinder, registers).next() {
+        Err(Error::NotCodeAddress(_)) => (),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+    assert!(StackFrames::new(&mut unwinder, Registers::default()).next().unwrap().is_none());
+}
+
+#[test]
+fn bad_frame_pointer() {
+    let mut unwinder = DwarfUnwinder::default();
+    unwinder.set_strategies(&[Strategy::FramePointer]);
+    let local = 0u64;
+    let rsp = &local as *const u64 as u64 & !7;
+    // null, below the stack pointer, misaligned, past the end of the stack, wrapping
+    for &rbp in &[0, rsp - 64, rsp + 4, rsp + (1 << 40), u64::MAX - 15] {
+        let mut registers = Registers::default();
+        registers[X86_64::RA] = Some(bad_frame_pointer as usize as u64 + 1);
+        registers[X86_64::RSP] = Some(rsp);
+        registers[X86_64::RBP] = Some(rbp);
+        match StackFrames::new(&mut unwinder, registers).next() {
+            Err(Error::NotStackAddress(x)) => assert_eq!(x, rbp),
+            x => panic!("0x{:x}: {:?}", rbp, x.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn stack_scan_off_stack() {
+    let mut unwinder = DwarfUnwinder::default();
+    unwinder.set_strategies(&[Strategy::StackScan]);
+    // nothing says where this "stack" ends, so it must not be scanned past its 4 words
+    let heap = vec![0u64; 4];
+    let rsp = heap.as_ptr() as u64;
+    let mut registers = Registers::default();
+    registers[X86_64::RA] = Some(stack_scan_off_stack as usize as u64 + 1);
+    registers[X86_64::RSP] = Some(rsp);
+    match StackFrames::new(&mut unwinder, registers).next() {
+        Err(Error::NotStackAddress(x)) => assert_eq!(x, rsp),
+        x => panic!("{:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn zero_return_address() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut registers = Registers::default();
+    registers[X86_64::RA] = Some(0);
+    let mut frames = StackFrames::new(&mut unwinder, registers);
+    assert!(frames.next().unwrap().is_none());
+    assert_eq!(frames.stop_reason(), Some(StopReason::EndOfStack));
+}
+
 #[inline(never)]
 fn test_frame_1() { test_frame_2() }
 
@@ -43,6 +564,7 @@ fn test_frame_3() {
         while let Some(_) = frames.next().unwrap() {
             our_trace.push(frames.registers()[16].unwrap() - 1);
         }
+        assert_eq!(frames.stop_reason(), Some(StopReason::EndOfStack));
     });
 
     for i in &our_trace {
@@ -54,3 +576,131 @@ fn test_frame_3() {
     let ref_trace = &ref_trace[ref_trace_len - our_trace_len..][..our_trace_len];
     assert_eq!(our_trace, ref_trace);
 }
+
+#[test]
+fn shared_cache() {
+    // the small one evicts on every frame
+    for &capacity in &[64, 2] {
+        let cache = Arc::new(UnwindCache::new(capacity));
+        let mut traces = Vec::new();
+        for _ in 0..3 {
+            let mut unwinder = DwarfUnwinder::default();
+            unwinder.set_cache(Some(cache.clone()));
+            let mut trace = Vec::new();
+            unwinder.trace(|frames| {
+                while let Some(frame) = frames.next().unwrap() {
+                    trace.push((frame.initial_address(), frames.registers()[16]));
+                }
+            });
+            traces.push(trace);
+        }
+        assert_eq!(traces[0], traces[1]);
+        assert_eq!(traces[1], traces[2]);
+    }
+}
+
+struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {}
+}
+
+#[inline(never)]
+fn frame_with_cleanup(f: &mut dyn FnMut()) {
+    let _guard = Guard;
+    f();
+}
+
+#[test]
+fn personality_and_lsda() {
+    let mut found = false;
+    frame_with_cleanup(&mut || {
+        DwarfUnwinder::default().trace(|frames| {
+            while let Some(frame) = frames.next().unwrap() {
+                if frame.initial_address() == frame_with_cleanup as usize as u64 {
+                    assert!(frame.personality().is_some());
+                    assert!(frame.lsda().is_some());
+                    found = true;
+                }
+            }
+        });
+    });
+    assert!(found);
+}
+
+#[test]
+fn lsda_landing_pad() {
+    let mut found = false;
+    frame_with_cleanup(&mut || {
+        DwarfUnwinder::default().trace(|frames| {
+            while let Some(frame) = frames.next().unwrap() {
+                if frame.initial_address() == frame_with_cleanup as usize as u64 {
+                    let pc = frames.registers()[X86_64::RA].unwrap() - 1;
+                    let lsda = unsafe { Lsda::parse(frame.lsda().unwrap(), frame.initial_address()) }.unwrap();
+                    let call_site = unsafe { lsda.call_site(pc) }.unwrap().unwrap();
+                    assert!(call_site.start <= pc && pc - call_site.start < call_site.len);
+                    assert!(call_site.landing_pad.is_some());
+                    assert_eq!(unsafe { lsda.actions(&call_site) }.unwrap(), vec![Action::Cleanup]);
+                    found = true;
+                }
+            }
+        });
+    });
+    assert!(found);
+}
+
+#[test]
+fn process_metadata() {
+    let metadata = ProcessMetadata::current(&DwarfUnwinder::default());
+    let exe = metadata.executable.clone().unwrap();
+    assert!(metadata.modules.iter().any(|module| module.path.as_ref() == Some(&exe)
+        && module.text.contains(process_metadata as usize as u64)));
+    assert_eq!(metadata.os, std::env::consts::OS);
+    assert!(metadata.timestamp.is_some());
+
+    let address = process_metadata as usize as u64;
+    let (module, relative) = metadata.module_relative(address).unwrap();
+    assert_eq!(module.path.as_ref(), Some(&exe));
+    assert_eq!(relative + module.bias, address);
+}
+
+#[test]
+fn normalize_trace() {
+    let mut unwinder = DwarfUnwinder::default();
+    let mut trace = ProcessTrace::new(vec![ThreadTrace::capture(&mut unwinder)], Some(ProcessMetadata::current(&unwinder)));
+    trace.normalize();
+    let metadata = trace.metadata.as_ref().unwrap();
+    let frames = &trace.threads[0].frames;
+    assert!(frames.iter().any(|frame| frame.module_relative.is_some()));
+    for frame in frames {
+        if let Some((i, relative)) = frame.module_relative {
+            assert_eq!(Some(relative + metadata.modules[i].bias), frame.registers[X86_64::RA]);
+        }
+    }
+}
+
+#[test]
+fn offline_trace() {
+    let module = TraceModule::new(AddrRange { start: 0x1000, end: 0x2000 }, 0x1000);
+    let mut registers = Registers::default();
+    registers[X86_64::RA] = Some(0x1800);
+    let captured = ThreadTrace::capture(&mut DwarfUnwinder::default());
+    let frames = captured.frames.iter().map(|frame| TraceFrame::new(frame.frame.clone(), registers.clone())).collect();
+    let mut trace = ProcessTrace::new(vec![ThreadTrace::new(frames, Some(StopReason::EndOfStack))],
+                                      Some(ProcessMetadata::new("linux".to_string(), vec![module])));
+    trace.normalize();
+    assert!(!trace.threads[0].frames.is_empty());
+    assert!(trace.threads[0].frames.iter().all(|frame| frame.module_relative == Some((0, 0x800))));
+}
+
+#[test]
+fn scrub_metadata() {
+    let mut metadata = ProcessMetadata::current(&DwarfUnwinder::default());
+    let exe = metadata.executable.clone().unwrap();
+    let modules = metadata.modules.len();
+    metadata.scrub(|path| path == exe);
+    assert!(metadata.executable.is_none());
+    assert_eq!(metadata.modules.len(), modules);
+    assert!(metadata.modules.iter().all(|module| module.path.as_ref() != Some(&exe)));
+    assert!(metadata.module_relative(scrub_metadata as usize as u64).is_some());
+}
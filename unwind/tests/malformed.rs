@@ -0,0 +1,121 @@
+//! Corrupt unwind info must make the unwinder give up, not panic. Registered blobs are
+//! seen by every unwinder in the process, so these get a test binary of their own.
+extern crate unwind;
+extern crate fallible_iterator;
+
+use unwind::{Unwinder, DwarfUnwinder, Registers};
+use unwind::lsda::Lsda;
+use fallible_iterator::FallibleIterator;
+
+// CIE: "zR" with absolute pointers, then an FDE for [start, start + len) whose
+// instructions are all DW_CFA_nop.
+fn eh_frame(start: u64, len: u64) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&20u32.to_ne_bytes());
+    data.extend_from_slice(&[0, 0, 0, 0, 1, b'z', b'R', 0, 1, 0x78, 16, 1, 0x00]);
+    data.extend_from_slice(&[0x0c, 0x07, 0x08, 0x90, 0x01, 0, 0]);
+    data.extend_from_slice(&28u32.to_ne_bytes());
+    data.extend_from_slice(&28u32.to_ne_bytes());
+    data.extend_from_slice(&start.to_ne_bytes());
+    data.extend_from_slice(&len.to_ne_bytes());
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(&0u32.to_ne_bytes());
+    data
+}
+
+// xorshift64, so failures reproduce
+fn garbage(state: &mut u64, len: usize) -> Vec<u8> {
+    (0..len).map(|_| {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state as u8
+    }).collect()
+}
+
+fn trace_depth() -> usize {
+    let mut depth = 0;
+    DwarfUnwinder::default().trace(|frames| {
+        while let Ok(Some(_)) = frames.next() {
+            depth += 1;
+        }
+    });
+    depth
+}
+
+#[test]
+fn malformed_eh_frames() {
+    let depth = trace_depth();
+    assert!(depth > 0);
+
+    let mut blobs = vec![
+        // runs past the end of the address space
+        eh_frame(0xffff_ffff_ffff_f000, 0x10_0000),
+        eh_frame(u64::MAX, u64::MAX),
+        // empty
+        eh_frame(0x1000, 0),
+    ];
+    let mut state = 0x2545_f491_4f6c_dd1d;
+    for len in 1..64 {
+        blobs.push(garbage(&mut state, len * 7));
+        // a valid header followed by junk
+        let mut blob = eh_frame(0x1000, 0x1000);
+        blob.truncate(len % blob.len());
+        blob.extend(garbage(&mut state, len));
+        blobs.push(blob);
+    }
+
+    let blobs: Vec<&'static [u8]> = blobs.into_iter().map(|blob| &*Box::leak(blob.into_boxed_slice())).collect();
+    for &blob in &blobs {
+        unsafe { unwind::register_frame(blob) };
+    }
+    // none of them describes this code
+    assert_eq!(trace_depth(), depth);
+    for blob in blobs {
+        assert!(unsafe { unwind::deregister_frame(blob.as_ptr()) });
+    }
+}
+
+#[test]
+fn untracked_registers() {
+    let mut registers = Registers::default();
+    registers[1000u16] = Some(1);
+    assert_eq!(registers[1000u16], None);
+    assert_eq!(registers, Registers::default());
+}
+
+// One call site covering [0x1000, 0x1010) with the given action table, and an
+// absolute type table.
+fn lsda_actions(actions: &[u8]) -> Result<Vec<unwind::lsda::Action>, unwind::Error> {
+    let mut data = vec![0xff, 0x00, 0x00, 0x01, 4, 0x00, 0x10, 0x08, 0x01];
+    data.extend_from_slice(actions);
+    unsafe {
+        let lsda = Lsda::parse(data.as_ptr() as u64, 0x1000)?;
+        let call_site = lsda.call_site(0x1000)?.unwrap();
+        lsda.actions(&call_site)
+    }
+}
+
+#[test]
+fn malformed_lsda() {
+    // a cleanup whose next record is itself
+    assert!(lsda_actions(&[0x00, 0x7f]).is_err());
+    // a type filter of 2^62, past the start of the address space
+    assert!(lsda_actions(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0xc0, 0x00, 0x00]).is_err());
+    assert_eq!(lsda_actions(&[0x00, 0x00]).unwrap(), vec![unwind::lsda::Action::Cleanup]);
+}
+
+#[test]
+fn inverted_range() {
+    let range = unwind::AddrRange { start: 0x2000, end: 0x1000 };
+    assert!(range.is_empty());
+    assert_eq!(range.len(), 0);
+}
+
+#[test]
+fn land_without_stack() {
+    let mut registers = Registers::default();
+    registers[unwind::X86_64::RSP] = Some(4);
+    registers[unwind::X86_64::RA] = Some(0x1000);
+    assert!(unsafe { unwind::glue::land(&registers) }.is_err());
+}
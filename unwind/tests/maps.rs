@@ -0,0 +1,149 @@
+extern crate unwind;
+
+use std::convert::TryInto;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use unwind::{DwarfUnwinder, ProcessMetadata};
+
+const PT_LOAD: u32 = 1;
+const ET_DYN: u16 = 3;
+const SHT_SYMTAB: u32 = 2;
+
+fn u16_at(elf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes(elf[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(elf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(elf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(elf: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(elf[offset..offset + 8].try_into().unwrap())
+}
+
+// The link-time address of `file_offset`, from the PT_LOAD header mapping it.
+fn link_address(elf: &[u8], file_offset: u64) -> u64 {
+    let (phoff, phentsize, phnum) = (u64_at(elf, 32) as usize, u16_at(elf, 54) as usize, u16_at(elf, 56) as usize);
+    (0..phnum).map(|i| phoff + i * phentsize).find_map(|ph| {
+        let (p_offset, p_vaddr, p_filesz) = (u64_at(elf, ph + 8), u64_at(elf, ph + 16), u64_at(elf, ph + 32));
+        if u32_at(elf, ph) == PT_LOAD && p_offset <= file_offset && file_offset < p_offset + p_filesz {
+            Some(p_vaddr + (file_offset - p_offset))
+        } else {
+            None
+        }
+    }).unwrap()
+}
+
+// The value of the symbol `name` in .symtab.
+fn symbol_address(elf: &[u8], name: &str) -> u64 {
+    let (shoff, shentsize, shnum) = (u64_at(elf, 40) as usize, u16_at(elf, 58) as usize, u16_at(elf, 60) as usize);
+    let section = |i: usize| shoff + i * shentsize;
+    let symtab = (0..shnum).map(section).find(|&sh| u32_at(elf, sh + 4) == SHT_SYMTAB).unwrap();
+    let strtab = u64_at(elf, section(u32_at(elf, symtab + 40) as usize) + 24) as usize;
+    let (offset, size) = (u64_at(elf, symtab + 24) as usize, u64_at(elf, symtab + 32) as usize);
+    (offset..offset + size).step_by(24).find_map(|sym| {
+        let start = strtab + u32_at(elf, sym) as usize;
+        let len = elf[start..].iter().position(|&b| b == 0).unwrap();
+        if &elf[start..start + len] == name.as_bytes() {
+            Some(u64_at(elf, sym + 8))
+        } else {
+            None
+        }
+    }).unwrap()
+}
+
+// The executable mapping of `path` holding `address`: start, end and file offset.
+fn mapping(maps: &str, path: &Path, address: u64) -> (u64, u64, u64) {
+    maps.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let mut range = fields[0].split('-').map(|x| u64::from_str_radix(x, 16).unwrap());
+        let (start, end) = (range.next().unwrap(), range.next().unwrap());
+        let offset = u64::from_str_radix(fields[2], 16).unwrap();
+        if fields[1].contains('x') && fields.get(5) == Some(&&*path.to_string_lossy()) && start <= address && address < end {
+            Some((start, end, offset))
+        } else {
+            None
+        }
+    }).unwrap()
+}
+
+// Test binaries are position independent by default, so this checks the load bias
+// math against what the kernel reports.
+#[cfg(target_os = "linux")]
+#[test]
+fn text_matches_proc_maps() {
+    let metadata = ProcessMetadata::current(&DwarfUnwinder::default());
+    let exe = metadata.executable.clone().unwrap();
+    let address = text_matches_proc_maps as usize as u64;
+    let (module, relative) = metadata.module_relative(address).unwrap();
+    assert_eq!(module.path.as_ref(), Some(&exe));
+
+    let maps = fs::read_to_string("/proc/self/maps").unwrap();
+    let (start, end, offset) = mapping(&maps, &exe, address);
+
+    // the whole executable segment is mapped r-x from the executable
+    assert!(start <= module.text.start && module.text.end <= end);
+    let elf = fs::read(&exe).unwrap();
+    assert_eq!(relative, link_address(&elf, address - start + offset));
+    assert_eq!(module.bias % 4096, 0);
+}
+
+#[no_mangle]
+pub extern "C" fn maps_helper_marker() {}
+
+// Run by `pie_helper_process` in a child: reports where the marker is, then waits
+// until the parent has read its maps.
+#[cfg(target_os = "linux")]
+#[test]
+fn pie_helper() {
+    if env::var_os("UNWIND_PIE_HELPER").is_none() {
+        return;
+    }
+    let address = maps_helper_marker as usize as u64;
+    let (_, relative) = ProcessMetadata::current(&DwarfUnwinder::default()).module_relative(address).unwrap();
+    // libtest may have started the line already
+    println!("\nmarker at {:x} {:x}", address, relative);
+    std::io::stdin().read_line(&mut String::new()).unwrap();
+}
+
+// Another process's addresses, biased by its own ASLR slide, must come back to the
+// link-time address of the symbol.
+#[cfg(target_os = "linux")]
+#[test]
+fn pie_helper_process() {
+    let exe = env::current_exe().unwrap();
+    let elf = fs::read(&exe).unwrap();
+    assert_eq!(u16_at(&elf, 16), ET_DYN);
+
+    let mut child = Command::new(&exe)
+        .args(&["--exact", "pie_helper", "--nocapture", "--test-threads=1"])
+        .env("UNWIND_PIE_HELPER", "1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let report = lines.find_map(|line| line.unwrap().strip_prefix("marker at ").map(String::from)).unwrap();
+    let mut fields = report.split(' ').map(|x| u64::from_str_radix(x, 16).unwrap());
+    let (address, reported) = (fields.next().unwrap(), fields.next().unwrap());
+
+    let maps = fs::read_to_string(format!("/proc/{}/maps", child.id())).unwrap();
+    child.stdin.take().unwrap().write_all(b"\n").unwrap();
+    assert!(child.wait().unwrap().success());
+
+    let (start, _, offset) = mapping(&maps, &exe, address);
+    let link = link_address(&elf, address - start + offset);
+    let bias = address - link;
+    assert_eq!(bias % 4096, 0);
+    assert_ne!(bias, 0);
+    assert_eq!(link, symbol_address(&elf, "maps_helper_marker"));
+    assert_eq!(reported, link);
+    // the same binary, so this process must agree despite its different slide
+    let local = maps_helper_marker as usize as u64;
+    let metadata = ProcessMetadata::current(&DwarfUnwinder::default());
+    assert_eq!(metadata.module_relative(local).unwrap().1, link);
+}
@@ -0,0 +1,322 @@
+//! Drives the `_Unwind_*` shim the way a language runtime does: raising an exception
+//! through a cleanup to a handler in two phases, and forced unwinding, with a
+//! personality routine built on `unwind::lsda`.
+//!
+//! The shim interposes std's unwinder in this binary (see `unwind::libunwind_shim`), so
+//! nothing here may panic while an exception is in flight.
+#![cfg(all(feature = "libunwind_shim", target_arch = "x86_64"))]
+
+extern crate unwind;
+extern crate libc;
+
+use libc::{c_int, c_void};
+
+use unwind::X86_64;
+use unwind::libunwind_shim::*;
+use unwind::lsda::{Lsda, Action};
+
+// catch_frame(inner, thrower, fixture) calls inner(thrower, fixture), returning the
+// exception its handler caught, or null.
+//
+// cleanup_frame(thrower, fixture) calls thrower(fixture). Its cleanup counts itself in
+// `fixture.cleanups` and resumes unwinding. args_cleanup_frame does the same with 16
+// bytes of arguments pushed for the call, which the landing pad expects to be gone.
+std::arch::global_asm!(r#"
+    .globl catch_frame
+    .type catch_frame,@function
+catch_frame:
+    .cfi_startproc
+    .cfi_personality 0x1b, test_personality
+    .cfi_lsda 0x1b, .Lcatch_frame_lsda
+    subq $8, %rsp
+    .cfi_def_cfa_offset 16
+    movq %rdi, %rax
+    movq %rsi, %rdi
+    movq %rdx, %rsi
+.Lcatch_call:
+    call *%rax
+.Lcatch_call_end:
+    xorl %eax, %eax
+    addq $8, %rsp
+    .cfi_def_cfa_offset 8
+    ret
+    .globl catch_frame_pad
+catch_frame_pad:
+    .cfi_def_cfa_offset 16
+    # the exception is in rax already
+    addq $8, %rsp
+    .cfi_def_cfa_offset 8
+    ret
+    .cfi_endproc
+    .globl catch_frame_end
+catch_frame_end:
+
+    .pushsection .gcc_except_table,"a",@progbits
+.Lcatch_frame_lsda:
+    .byte 0xff
+    # absolute type infos
+    .byte 0x00
+    .uleb128 .Lcatch_types_end - .Lcatch_types_offset
+.Lcatch_types_offset:
+    .byte 0x01
+    .uleb128 .Lcatch_call_sites_end - .Lcatch_call_sites
+.Lcatch_call_sites:
+    .uleb128 .Lcatch_call - catch_frame
+    .uleb128 .Lcatch_call_end - .Lcatch_call
+    .uleb128 catch_frame_pad - catch_frame
+    .uleb128 1
+.Lcatch_call_sites_end:
+    # catch type 1, no next action
+    .byte 1
+    .byte 0
+    # type 1: catch anything
+    .quad 0
+.Lcatch_types_end:
+    .popsection
+
+    .globl cleanup_frame
+    .type cleanup_frame,@function
+cleanup_frame:
+    .cfi_startproc
+    .cfi_personality 0x1b, test_personality
+    .cfi_lsda 0x1b, .Lcleanup_frame_lsda
+    subq $8, %rsp
+    .cfi_def_cfa_offset 16
+    movq %rsi, (%rsp)
+    movq %rdi, %rax
+    movq %rsi, %rdi
+.Lcleanup_call:
+    call *%rax
+.Lcleanup_call_end:
+    addq $8, %rsp
+    .cfi_def_cfa_offset 8
+    ret
+.Lcleanup_pad:
+    .cfi_def_cfa_offset 16
+    movq (%rsp), %rcx
+    incq (%rcx)
+    movq %rax, %rdi
+    call _Unwind_Resume@PLT
+    .cfi_endproc
+
+    .pushsection .gcc_except_table,"a",@progbits
+.Lcleanup_frame_lsda:
+    .byte 0xff
+    .byte 0xff
+    .byte 0x01
+    .uleb128 .Lcleanup_call_sites_end - .Lcleanup_call_sites
+.Lcleanup_call_sites:
+    .uleb128 .Lcleanup_call - cleanup_frame
+    .uleb128 .Lcleanup_call_end - .Lcleanup_call
+    .uleb128 .Lcleanup_pad - cleanup_frame
+    .uleb128 0
+.Lcleanup_call_sites_end:
+    .popsection
+
+    .globl args_cleanup_frame
+    .type args_cleanup_frame,@function
+args_cleanup_frame:
+    .cfi_startproc
+    .cfi_personality 0x1b, test_personality
+    .cfi_lsda 0x1b, .Largs_cleanup_frame_lsda
+    subq $8, %rsp
+    .cfi_def_cfa_offset 16
+    movq %rsi, (%rsp)
+    movq %rdi, %rax
+    movq %rsi, %rdi
+    pushq $0
+    .cfi_def_cfa_offset 24
+    pushq $0
+    .cfi_def_cfa_offset 32
+    # DW_CFA_GNU_args_size 16
+    .cfi_escape 0x2e, 0x10
+.Largs_cleanup_call:
+    call *%rax
+.Largs_cleanup_call_end:
+    addq $16, %rsp
+    .cfi_escape 0x2e, 0x00
+    .cfi_def_cfa_offset 16
+    addq $8, %rsp
+    .cfi_def_cfa_offset 8
+    ret
+.Largs_cleanup_pad:
+    .cfi_def_cfa_offset 16
+    movq (%rsp), %rcx
+    incq (%rcx)
+    movq %rax, %rdi
+    call _Unwind_Resume@PLT
+    .cfi_endproc
+
+    .pushsection .gcc_except_table,"a",@progbits
+.Largs_cleanup_frame_lsda:
+    .byte 0xff
+    .byte 0xff
+    .byte 0x01
+    .uleb128 .Largs_cleanup_call_sites_end - .Largs_cleanup_call_sites
+.Largs_cleanup_call_sites:
+    .uleb128 .Largs_cleanup_call - args_cleanup_frame
+    .uleb128 .Largs_cleanup_call_end - .Largs_cleanup_call
+    .uleb128 .Largs_cleanup_pad - args_cleanup_frame
+    .uleb128 0
+.Largs_cleanup_call_sites_end:
+    .popsection
+"#);
+
+type Thrower = extern "C" fn(*mut Fixture);
+
+#[allow(improper_ctypes)] // the fixtures only pass the pointers on
+extern "C" {
+    fn catch_frame(inner: unsafe extern "C" fn(Thrower, *mut Fixture), thrower: Thrower, fixture: *mut Fixture)
+                   -> *mut _Unwind_Exception;
+    fn catch_frame_pad();
+    fn catch_frame_end();
+    fn cleanup_frame(thrower: Thrower, fixture: *mut Fixture);
+    fn args_cleanup_frame(thrower: Thrower, fixture: *mut Fixture);
+}
+
+#[repr(C)]
+struct Fixture {
+    // bumped by the cleanups, so it must come first
+    cleanups: u64,
+    exception: *mut _Unwind_Exception,
+    stops: u64,
+    // what raising returned, if it did
+    returned: Option<_Unwind_Reason_Code>,
+}
+
+impl Fixture {
+    fn new() -> Fixture {
+        let exception = _Unwind_Exception {
+            exception_class: u64::from_be_bytes(*b"UNWDTEST"),
+            exception_cleanup: delete_exception,
+            private_contptr: None,
+        };
+        Fixture { cleanups: 0, exception: Box::into_raw(Box::new(exception)), stops: 0, returned: None }
+    }
+}
+
+extern "C" fn delete_exception(_: _Unwind_Reason_Code, exception: *mut _Unwind_Exception) {
+    unsafe { drop(Box::from_raw(exception)) };
+}
+
+// Runs every cleanup and catches everything, like a C++ personality with `catch (...)`.
+#[no_mangle]
+pub extern "C" fn test_personality(_version: c_int, actions: c_int, _class: u64, exception: *mut _Unwind_Exception,
+                                   ctx: *mut _Unwind_Context) -> _Unwind_Reason_Code {
+    unsafe {
+        let lsda = Lsda::parse(_Unwind_GetLanguageSpecificData(ctx) as u64, _Unwind_GetRegionStart(ctx) as u64).unwrap();
+        let call_site = match lsda.call_site(_Unwind_GetIP(ctx) as u64 - 1).unwrap() {
+            Some(call_site) => call_site,
+            None => return _Unwind_Reason_Code::_URC_CONTINUE_UNWIND,
+        };
+        let landing_pad = match call_site.landing_pad {
+            Some(landing_pad) => landing_pad,
+            None => return _Unwind_Reason_Code::_URC_CONTINUE_UNWIND,
+        };
+        let pad_actions = lsda.actions(&call_site).unwrap();
+        let catches = pad_actions.iter().any(|action| match *action {
+            Action::Catch { .. } => true,
+            _ => false,
+        });
+
+        if actions & _Unwind_Action::_UA_SEARCH_PHASE as c_int != 0 {
+            return if catches {
+                _Unwind_Reason_Code::_URC_HANDLER_FOUND
+            } else {
+                _Unwind_Reason_Code::_URC_CONTINUE_UNWIND
+            };
+        }
+        if actions & _Unwind_Action::_UA_HANDLER_FRAME as c_int != 0 || pad_actions.contains(&Action::Cleanup) {
+            _Unwind_SetGR(ctx, X86_64::RAX.0 as c_int, exception as usize);
+            _Unwind_SetIP(ctx, landing_pad as usize);
+            return _Unwind_Reason_Code::_URC_INSTALL_CONTEXT;
+        }
+        _Unwind_Reason_Code::_URC_CONTINUE_UNWIND
+    }
+}
+
+extern "C" fn raise(fixture: *mut Fixture) {
+    unsafe { (*fixture).returned = Some(_Unwind_RaiseException((*fixture).exception)) };
+}
+
+#[test]
+fn raise_exception() {
+    let mut fixture = Fixture::new();
+    let caught = unsafe { catch_frame(cleanup_frame, raise, &mut fixture) };
+    assert_eq!(fixture.returned, None);
+    assert_eq!(caught, fixture.exception);
+    assert_eq!(fixture.cleanups, 1);
+    unsafe { _Unwind_DeleteException(caught) };
+}
+
+#[test]
+fn resume_with_args_size() {
+    let mut fixture = Fixture::new();
+    let caught = unsafe { catch_frame(args_cleanup_frame, raise, &mut fixture) };
+    assert_eq!(fixture.returned, None);
+    assert_eq!(caught, fixture.exception);
+    assert_eq!(fixture.cleanups, 1);
+    unsafe { _Unwind_DeleteException(caught) };
+}
+
+extern "C" fn force(fixture: *mut Fixture) {
+    unsafe { (*fixture).returned = Some(_Unwind_ForcedUnwind((*fixture).exception, stop, fixture as *mut c_void)) };
+}
+
+// Lets the cleanups below catch_frame run, then leaves through its landing pad, like
+// the longjmp ending a thread cancellation.
+extern "C" fn stop(_version: c_int, actions: c_int, _class: u64, exception: *mut _Unwind_Exception,
+                   ctx: *mut _Unwind_Context, argument: *mut c_void) -> _Unwind_Reason_Code {
+    unsafe {
+        let fixture = argument as *mut Fixture;
+        (*fixture).stops += 1;
+        let ip = _Unwind_GetIP(ctx) as u64;
+        let in_catch_frame = ip > catch_frame as usize as u64 && ip <= catch_frame_end as usize as u64;
+        if actions & _Unwind_Action::_UA_END_OF_STACK as c_int == 0 && in_catch_frame {
+            _Unwind_SetGR(ctx, X86_64::RAX.0 as c_int, exception as usize);
+            _Unwind_SetIP(ctx, catch_frame_pad as usize);
+            unwind::glue::land(&*(*ctx).registers).unwrap();
+        }
+        _Unwind_Reason_Code::_URC_NO_REASON
+    }
+}
+
+#[test]
+fn forced_unwind() {
+    let mut fixture = Fixture::new();
+    let caught = unsafe { catch_frame(cleanup_frame, force, &mut fixture) };
+    assert_eq!(fixture.returned, None);
+    assert_eq!(caught, fixture.exception);
+    assert_eq!(fixture.cleanups, 1);
+    // force, cleanup_frame and catch_frame
+    assert_eq!(fixture.stops, 3);
+    unsafe { _Unwind_DeleteException(caught) };
+}
+
+extern "C" fn collect(ctx: *mut _Unwind_Context, frames: *mut c_void) -> _Unwind_Reason_Code {
+    unsafe {
+        let mut ip_before_insn = -1;
+        let ip = _Unwind_GetIPInfo(ctx, &mut ip_before_insn);
+        (*(frames as *mut Vec<(usize, c_int)>)).push((ip, ip_before_insn));
+    }
+    _Unwind_Reason_Code::_URC_NO_REASON
+}
+
+#[inline(never)]
+fn backtrace_here() -> Vec<(usize, c_int)> {
+    let mut frames = Vec::new();
+    let code = unsafe { _Unwind_Backtrace(collect, &mut frames as *mut _ as *mut c_void) };
+    assert_eq!(code, _Unwind_Reason_Code::_URC_END_OF_STACK);
+    frames
+}
+
+#[test]
+fn backtrace() {
+    let frames = backtrace_here();
+    // the shim's own frames are left out
+    let (ip, ip_before_insn) = frames[0];
+    assert_eq!(ip_before_insn, 0);
+    let function = unsafe { _Unwind_FindEnclosingFunction((ip - 1) as *mut c_void) };
+    assert_eq!(function as usize, backtrace_here as usize);
+    assert!(frames.len() > 1);
+}
@@ -0,0 +1,144 @@
+extern crate unwind;
+extern crate fallible_iterator;
+extern crate libc;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use unwind::{Unwinder, DwarfUnwinder, Registers, StackFrames};
+use fallible_iterator::FallibleIterator;
+
+thread_local! {
+    static FUNCTIONS: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+    static TRAMPOLINES: RefCell<usize> = RefCell::new(0);
+    static INTERRUPTED: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+    static UNWINDER: RefCell<DwarfUnwinder> = RefCell::new(DwarfUnwinder::default());
+    static PCS: RefCell<([u64; 64], usize)> = RefCell::new(([0; 64], 0));
+    static TRACED: RefCell<Vec<u64>> = RefCell::new(Vec::new());
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Counts the allocations of threads that ask for it.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.try_with(Cell::get).unwrap_or(false) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+extern "C" fn handler(_: libc::c_int) {
+    DwarfUnwinder::default().trace(|frames| {
+        while let Some(frame) = frames.next().unwrap() {
+            FUNCTIONS.with(|f| f.borrow_mut().push(frame.initial_address()));
+            if frame.is_signal_trampoline() {
+                TRAMPOLINES.with(|t| *t.borrow_mut() += 1);
+            }
+        }
+    });
+}
+
+#[inline(never)]
+fn raise_signal() {
+    unsafe { libc::raise(libc::SIGUSR1) };
+}
+
+#[test]
+fn through_signal_handler() {
+    unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) };
+    raise_signal();
+
+    FUNCTIONS.with(|f| {
+        let functions = f.borrow();
+        assert!(functions.contains(&(raise_signal as usize as u64)));
+        assert!(functions.contains(&(through_signal_handler as usize as u64)));
+    });
+    assert_eq!(TRAMPOLINES.with(|t| *t.borrow()), 1);
+}
+
+extern "C" fn siginfo_handler(_: libc::c_int, _: *mut libc::siginfo_t, ucontext: *mut libc::c_void) {
+    let mut unwinder = DwarfUnwinder::default();
+    let registers = unsafe { Registers::from_ucontext(ucontext) };
+    let mut frames = StackFrames::from_interrupted(&mut unwinder, registers);
+    while let Some(frame) = frames.next().unwrap() {
+        INTERRUPTED.with(|f| f.borrow_mut().push(frame.initial_address()));
+    }
+}
+
+#[inline(never)]
+fn raise_siginfo_signal() {
+    unsafe { libc::raise(libc::SIGUSR2) };
+}
+
+#[test]
+fn from_ucontext() {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = siginfo_handler as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut());
+    }
+    raise_siginfo_signal();
+
+    INTERRUPTED.with(|f| {
+        let functions = f.borrow();
+        // starts at the interrupted code, not in the handler
+        assert!(!functions.contains(&(siginfo_handler as usize as u64)));
+        assert!(functions.contains(&(raise_siginfo_signal as usize as u64)));
+        assert!(functions.contains(&(from_ucontext as usize as u64)));
+    });
+}
+
+extern "C" fn capture_handler(_: libc::c_int) {
+    UNWINDER.with(|unwinder| PCS.with(|pcs| {
+        let mut unwinder = unwinder.borrow_mut();
+        let (ref mut buf, ref mut len) = *pcs.borrow_mut();
+        COUNTING.with(|counting| counting.set(true));
+        *len = unwinder.trace_into(buf);
+        COUNTING.with(|counting| counting.set(false));
+
+        // the same frames, the usual way
+        unwinder.trace(|frames| {
+            while let Ok(Some(_)) = frames.next() {
+                let pc = frames.registers()[unwind::X86_64::RA].unwrap();
+                TRACED.with(|traced| traced.borrow_mut().push(pc));
+            }
+        });
+    }));
+}
+
+#[test]
+fn allocation_free_capture() {
+    // Set up the thread locals outside the handler, and load any .debug_frame, which
+    // trace_into only uses once loaded.
+    UNWINDER.with(|unwinder| unwinder.borrow_mut().trace(|frames| while let Ok(Some(_)) = frames.next() {}));
+    PCS.with(|_| ());
+    TRACED.with(|_| ());
+
+    unsafe { libc::signal(libc::SIGALRM, capture_handler as libc::sighandler_t) };
+    unsafe { libc::raise(libc::SIGALRM) };
+
+    assert_eq!(ALLOCATIONS.load(Ordering::SeqCst), 0);
+    PCS.with(|pcs| TRACED.with(|traced| {
+        let (ref buf, len) = *pcs.borrow();
+        let traced = traced.borrow();
+        assert!(len < buf.len());
+        // they differ in their own frames only
+        let common = len.min(traced.len()) - 2;
+        assert!(common > 3);
+        assert_eq!(buf[len - common..len], traced[traced.len() - common..]);
+    }));
+}